@@ -0,0 +1,41 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A Nexmark bid, as found in `host/data/bids.csv`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bid {
+    pub auction: u64,
+    pub bidder: u64,
+    pub price: u64,
+    pub channel: String,
+    pub url: String,
+    pub date_time: u64,
+    pub extra: String,
+}
+
+impl FromStr for Bid {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.trim_end().splitn(7, ',').collect();
+        let [auction, bidder, price, channel, url, date_time, extra] = fields[..] else {
+            return Err(format!("expected 7 fields, found {}", fields.len()));
+        };
+        let number = |name: &str, field: &str| {
+            field
+                .parse::<u64>()
+                .map_err(|e| format!("invalid {name} {field:?}: {e}"))
+        };
+        Ok(Bid {
+            auction: number("auction", auction)?,
+            bidder: number("bidder", bidder)?,
+            price: number("price", price)?,
+            channel: channel.to_owned(),
+            url: url.to_owned(),
+            date_time: number("date_time", date_time)?,
+            extra: extra.to_owned(),
+        })
+    }
+}
@@ -15,9 +15,32 @@ pub mod bindings {
                 "pkg:component/intf#test3",
                 "pkg:component/intf#test4",
                 "pkg:component/intf#[method]session.infer",
+                "pkg:component/intf#remember",
+                "pkg:component/intf#count-ticks",
+                "pkg:component/intf#count-words",
+                "pkg:component/intf#swap",
+                "pkg:component/intf#aggregate",
                 "pkg:component/intf#get-files-p3",
+                "pkg:component/intf#read-file",
+                "pkg:component/intf#write-file",
+                "pkg:component/intf#sample",
+                "pkg:component/intf#throttle",
+                "pkg:component/intf#print",
+                "wasi:cli/run@0.3.0#run",
             ],
             imports: [
+                "pkg:component/host-services#get",
+                "pkg:component/host-services#put",
+                "pkg:component/host-services#log",
+                "pkg:component/host-services#increment",
+                "pkg:component/host-services#infer",
+                "pkg:component/host-services#ticks",
+                "pkg:component/keyvalue#get",
+                "pkg:component/keyvalue#set",
+                "pkg:component/keyvalue#delete",
+                "pkg:component/keyvalue#increment",
+                "pkg:component/keyvalue#get-many",
+                "pkg:component/keyvalue#set-many",
                 "wasi:cli/stdin@0.3.0#get-stdin",
                 "wasi:cli/stdout@0.3.0#set-stdout",
                 "wasi:cli/stderr@0.3.0#set-stderr",
@@ -68,22 +91,38 @@ pub mod bindings {
     export!(Component);
 }
 
+mod bid;
+mod state;
+
 use std::io::Read;
 use std::net::TcpListener;
 use std::net::TcpStream;
 
+use bid::Bid;
+use bindings::exports::pkg::component::intf::Error;
 use bindings::exports::pkg::component::intf::Guest;
 use bindings::exports::pkg::component::intf::GuestSession;
 use bindings::exports::pkg::component::intf::Request;
 use bindings::exports::pkg::component::intf::Response;
+use bindings::exports::pkg::component::intf::Sample;
 use bindings::exports::pkg::component::intf::SessionBorrow;
+use bindings::exports::pkg::component::intf::WindowStats;
+use bindings::pkg::component::host_services;
+use bindings::pkg::component::host_services::Level;
+use bindings::pkg::component::keyvalue;
+use bindings::wasi::cli::stdin::get_stdin;
+use bindings::wasi::cli::stdout::set_stdout;
+use bindings::wasi::clocks::monotonic_clock;
+use bindings::wasi::clocks::wall_clock;
 use bindings::wasi::filesystem;
 use bindings::wasi::filesystem::preopens::get_directories;
 use bindings::wasi::filesystem::types::Descriptor;
 use bindings::wasi::filesystem::types::DescriptorFlags;
 use bindings::wasi::filesystem::types::DirectoryEntry;
+use bindings::wasi::filesystem::types::ErrorCode;
 use bindings::wasi::filesystem::types::OpenFlags;
 use bindings::wasi::filesystem::types::PathFlags;
+use bindings::wasi::random::random;
 use bindings::wasi::sockets::types::IpAddressFamily;
 use bindings::wasi::sockets::types::IpSocketAddress;
 use bindings::wasi::sockets::types::Ipv4SocketAddress;
@@ -98,19 +137,56 @@ use wit_bindgen::rt::async_support::FutureReader;
 use wit_bindgen::rt::async_support::StreamReader;
 use wit_bindgen::rt::async_support::StreamWriter;
 
+/// A handle on an entry of [`state::State::sessions`], which is removed when
+/// the handle is dropped.
 pub struct Session {
-    last_response: String,
+    id: u64,
 }
 
 impl GuestSession for Session {
     fn new() -> Self {
         Self {
-            last_response: String::new(),
+            id: state::with(|state| state.open_session()),
         }
     }
 
+    /// Asks the host's model backend, keeping the prompt in the host's
+    /// key-value store and counting calls.
     async fn infer(&self, request: Request) -> Response {
-        todo!()
+        host_services::put(
+            "last-prompt".to_owned(),
+            request.message.clone().into_bytes(),
+        )
+        .await;
+        host_services::increment("infer".to_owned(), 1).await;
+        let message = match host_services::infer(request.message).await {
+            Ok(message) => message,
+            Err(e) => {
+                host_services::log(Level::Warn, format!("inference failed: {e}")).await;
+                self.last_response()
+            }
+        };
+        state::with(|state| state.sessions.insert(self.id, message.clone()));
+        Response { message }
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn last_response(&self) -> String {
+        state::with(|state| state.sessions.get(&self.id).cloned().unwrap_or_default())
+    }
+
+    fn resume(id: u64) -> Option<bindings::exports::pkg::component::intf::Session> {
+        state::with(|state| state.resume_session(id))
+            .then(|| bindings::exports::pkg::component::intf::Session::new(Session { id }))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        state::with(|state| state.close_session(self.id));
     }
 }
 
@@ -151,6 +227,79 @@ impl Guest for bindings::Component {
         rx
     }
 
+    async fn remember(key: String, value: Vec<u8>) -> Option<Vec<u8>> {
+        let previous = host_services::get(key.clone()).await;
+        host_services::log(Level::Debug, format!("remembering {key}")).await;
+        host_services::put(key, value).await;
+        previous
+    }
+
+    /// Collects `count` ticks from the host, doing some work of its own
+    /// between them, and then drops the stream, which stops the host's
+    /// ticker.
+    async fn count_ticks(interval: u64, count: u32) -> Vec<u64> {
+        let mut ticks = host_services::ticks(interval).await;
+        let mut seqs = Vec::new();
+        while seqs.len() < count as usize {
+            let Some(batch) = ticks.next().await else {
+                break;
+            };
+            for tick in batch {
+                host_services::increment("ticks".to_owned(), 1).await;
+                seqs.push(tick.seq);
+            }
+        }
+        seqs.truncate(count as usize);
+        seqs
+    }
+
+    /// Counts each word of `text` in the key-value store, returning the
+    /// running total after every occurrence.
+    async fn count_words(text: String) -> Result<Vec<(String, i64)>, String> {
+        let mut totals = Vec::new();
+        for word in text.split_whitespace() {
+            let total = keyvalue::increment(format!("words/{word}"), 1).await?;
+            totals.push((word.to_owned(), total));
+        }
+        Ok(totals)
+    }
+
+    /// Replaces the values of `entries`, returning the ones they had.
+    async fn swap(entries: Vec<(String, Vec<u8>)>) -> Result<Vec<Option<Vec<u8>>>, String> {
+        let keys = entries.iter().map(|(key, _)| key.clone()).collect();
+        let previous = keyvalue::get_many(keys).await?;
+        keyvalue::set_many(entries).await?;
+        Ok(previous)
+    }
+
+    async fn aggregate(mut lines: StreamReader<String>, width: u64) -> Vec<WindowStats> {
+        let width = width.max(1);
+        let mut closed = Vec::new();
+        while let Some(batch) = lines.next().await {
+            for line in batch {
+                let Ok(bid) = line.parse::<Bid>() else {
+                    continue;
+                };
+                closed.extend(state::with(|state| state.windows.push(&bid, width)));
+            }
+        }
+        closed.into_iter().map(window_stats).collect()
+    }
+
+    fn flush() -> Vec<WindowStats> {
+        let closed = state::with(|state| state.windows.flush());
+        closed.into_iter().map(window_stats).collect()
+    }
+
+    fn snapshot() -> Vec<u8> {
+        state::with(|state| state.snapshot())
+    }
+
+    fn restore(snapshot: Vec<u8>) -> Result<(), String> {
+        let restored = state::State::restore(&snapshot)?;
+        state::with(|state| state.replace(restored))
+    }
+
     async fn get_files_p3() -> String {
         let mut string = String::new();
         for (desc, name) in get_directories() {
@@ -166,6 +315,71 @@ impl Guest for bindings::Component {
         string
     }
 
+    async fn read_file(path: String) -> (StreamReader<u8>, FutureReader<Result<(), Error>>) {
+        let (mut tx, rx) = bindings::wit_stream::new::<u8>();
+        let (result_tx, result_rx) = bindings::wit_future::new::<Result<(), Error>>();
+        async_support::spawn(async move {
+            let result = async move {
+                let file = open(&path, OpenFlags::empty(), DescriptorFlags::READ).await?;
+                let (mut data, result) = file.read_via_stream(0).await;
+                while let Some(chunk) = data.next().await {
+                    tx.write(chunk).await;
+                }
+                match result.await {
+                    Some(result) => Ok(result?),
+                    None => Err(Error::Filesystem("read was cancelled".to_owned())),
+                }
+            };
+            // The stream is closed by now, so the reader sees its end first.
+            result_tx.write(result.await).await;
+        });
+        (rx, result_rx)
+    }
+
+    async fn write_file(path: String, data: StreamReader<u8>) -> FutureReader<Result<(), Error>> {
+        let (tx, rx) = bindings::wit_future::new::<Result<(), Error>>();
+        async_support::spawn(async move {
+            let result = async {
+                let flags = OpenFlags::CREATE | OpenFlags::TRUNCATE;
+                let file = open(&path, flags, DescriptorFlags::WRITE).await?;
+                file.write_via_stream(data, 0).await?;
+                Ok(())
+            };
+            tx.write(result.await).await;
+        });
+        rx
+    }
+
+    async fn sample(len: u32) -> Sample {
+        let now = wall_clock::now();
+        Sample {
+            monotonic: monotonic_clock::now(),
+            wall_seconds: now.seconds,
+            wall_nanoseconds: now.nanoseconds,
+            random: random::get_random_bytes(len.into()),
+        }
+    }
+
+    async fn throttle(mut input: StreamReader<String>, interval: u64) -> StreamReader<String> {
+        let (mut tx, rx) = bindings::wit_stream::new::<String>();
+        async_support::spawn(async move {
+            while let Some(items) = input.next().await {
+                for item in items {
+                    monotonic_clock::wait_for(interval).await;
+                    tx.write(vec![item]).await;
+                }
+            }
+        });
+        rx
+    }
+
+    async fn print(stdout: String, stderr: String) {
+        println!("{stdout}");
+        if !stderr.is_empty() {
+            eprintln!("{stderr}");
+        }
+    }
+
     // TODO:
     //
     // async fn read_tcp(...) -> ... {
@@ -186,3 +400,89 @@ impl Guest for bindings::Component {
     //     }
     // }
 }
+
+/// Reads Nexmark bids as CSV from stdin and writes them to stdout as JSON
+/// lines. Malformed lines are reported on stderr and make the command fail
+/// once all input has been processed.
+impl bindings::exports::wasi::cli::run::Guest for bindings::Component {
+    async fn run() -> Result<(), ()> {
+        let mut stdin = get_stdin().await;
+        let (mut tx, rx) = bindings::wit_stream::new::<u8>();
+        let transform = async move {
+            let mut ok = true;
+            let mut pending = Vec::new();
+            while let Some(chunk) = stdin.next().await {
+                pending.extend(chunk);
+                while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = pending.drain(..=end).collect();
+                    ok &= write_bid(&mut tx, &line).await;
+                }
+            }
+            if !pending.is_empty() {
+                ok &= write_bid(&mut tx, &pending).await;
+            }
+            ok
+        };
+        let ((), ok) = futures::join!(set_stdout(rx), transform);
+        if ok {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+async fn write_bid(tx: &mut StreamWriter<u8>, line: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(line);
+    if line.trim().is_empty() {
+        return true;
+    }
+    match line.parse::<Bid>() {
+        Ok(bid) => {
+            let mut json = serde_json::to_vec(&bid).unwrap();
+            json.push(b'\n');
+            tx.write(json).await;
+            true
+        }
+        Err(e) => {
+            eprintln!("skipping malformed bid: {e}");
+            false
+        }
+    }
+}
+
+fn window_stats((start, stats): (u64, state::Stats)) -> WindowStats {
+    WindowStats {
+        start,
+        bids: stats.bids,
+        max_price: stats.max_price,
+        total_price: stats.total_price,
+    }
+}
+
+impl From<ErrorCode> for Error {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Quota => Error::QuotaExceeded,
+            code => Error::Filesystem(format!("{code:?}")),
+        }
+    }
+}
+
+/// Opens `path` relative to the preopen named by its first segment, e.g.
+/// `scratch/out.bin` opens `out.bin` inside the `scratch` preopen.
+async fn open(
+    path: &str,
+    open_flags: OpenFlags,
+    flags: DescriptorFlags,
+) -> Result<Descriptor, Error> {
+    let (preopen, path) = path.split_once('/').unwrap_or((path, ""));
+    let (dir, _) = get_directories()
+        .into_iter()
+        .find(|(_, name)| name == preopen)
+        .ok_or_else(|| Error::NoSuchPreopen(preopen.to_owned()))?;
+    let file = dir
+        .open_at(PathFlags::empty(), path.to_owned(), open_flags, flags)
+        .await?;
+    Ok(file)
+}
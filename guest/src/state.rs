@@ -0,0 +1,132 @@
+//! The instance's logical state, which `snapshot` and `restore` move between
+//! instances.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::bid::Bid;
+
+/// Bumped whenever [`State`] changes shape, so an old snapshot is rejected
+/// rather than misread.
+const VERSION: u32 = 1;
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::default();
+}
+
+/// Runs `f` on the instance's state.
+pub fn with<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    next_session: u64,
+    /// The last response of every session that has not been closed.
+    pub sessions: BTreeMap<u64, String>,
+    /// Sessions the host holds a handle on. Handles do not move with a
+    /// snapshot, so sessions brought in by `restore` start without one.
+    #[serde(skip)]
+    live: BTreeSet<u64>,
+    pub windows: Windows,
+}
+
+impl State {
+    pub fn open_session(&mut self) -> u64 {
+        let id = self.next_session;
+        self.next_session += 1;
+        self.sessions.insert(id, String::new());
+        self.live.insert(id);
+        id
+    }
+
+    /// Hands out the one handle on session `id`, if it exists and nobody
+    /// holds a handle on it yet.
+    pub fn resume_session(&mut self, id: u64) -> bool {
+        self.sessions.contains_key(&id) && self.live.insert(id)
+    }
+
+    /// Forgets session `id` once its handle is dropped.
+    pub fn close_session(&mut self, id: u64) {
+        self.sessions.remove(&id);
+        self.live.remove(&id);
+    }
+
+    /// Replaces this state with `restored`, unless sessions are open: their
+    /// handles would otherwise point at sessions that no longer exist, or
+    /// alias restored ones with the same id.
+    pub fn replace(&mut self, restored: State) -> Result<(), String> {
+        if !self.live.is_empty() {
+            return Err(format!(
+                "cannot restore while {} sessions are open",
+                self.live.len()
+            ));
+        }
+        *self = restored;
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(&(VERSION, self)).unwrap()
+    }
+
+    pub fn restore(snapshot: &[u8]) -> Result<Self, String> {
+        let (version, state): (u32, State) =
+            serde_json::from_slice(snapshot).map_err(|e| format!("invalid snapshot: {e}"))?;
+        if version != VERSION {
+            return Err(format!(
+                "snapshot has version {version}, expected {VERSION}"
+            ));
+        }
+        Ok(state)
+    }
+}
+
+/// Tumbling windows over bid time. A window closes once a bid at or past its
+/// end has been seen; bids for windows that are already closed are dropped.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Windows {
+    /// The largest `date_time` seen so far.
+    watermark: u64,
+    /// Open windows by start time.
+    open: BTreeMap<u64, Stats>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Stats {
+    pub bids: u64,
+    pub max_price: u64,
+    pub total_price: u64,
+}
+
+impl Windows {
+    /// Adds `bid` to its window of `width` and returns the windows that
+    /// closed, by start time.
+    pub fn push(&mut self, bid: &Bid, width: u64) -> Vec<(u64, Stats)> {
+        let start = bid.date_time - bid.date_time % width;
+        if start + width <= self.watermark {
+            return Vec::new();
+        }
+        let stats = self.open.entry(start).or_default();
+        stats.bids += 1;
+        stats.max_price = stats.max_price.max(bid.price);
+        stats.total_price += bid.price;
+        self.watermark = self.watermark.max(bid.date_time);
+        if self.watermark < width {
+            return Vec::new();
+        }
+        let open = self.open.split_off(&(self.watermark - width + 1));
+        std::mem::replace(&mut self.open, open)
+            .into_iter()
+            .collect()
+    }
+
+    /// Closes every open window.
+    pub fn flush(&mut self) -> Vec<(u64, Stats)> {
+        std::mem::take(&mut self.open).into_iter().collect()
+    }
+}
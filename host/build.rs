@@ -1,28 +1,341 @@
 use std::env;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 
+use sha2::Digest;
+use sha2::Sha256;
+use wasm_compose::composer::ComponentComposer;
 use wasmparser::Validator;
 use wasmparser::WasmFeatures;
 use wit_component::ComponentEncoder;
 
+// Keeps `src/engine.rs` naming it `wasmtime`.
+#[cfg(feature = "precompile")]
+extern crate wasmtime_precompile as wasmtime;
+
+/// Overrides the guests' cargo profile, `debug` or `release`. Defaults to the
+/// host's own profile.
+const GUEST_PROFILE: &str = "GUEST_PROFILE";
+
+/// The guests' target: `wasm32-wasip1`, componentized with the preview1
+/// adapter, or `wasm32-wasip2`, which rustc already emits as a component.
+const GUEST_TARGET: &str = "GUEST_TARGET";
+
+/// Path of a prebuilt `wasi_snapshot_preview1.wasm`, to skip building the
+/// adapter.
+const WASI_ADAPTER: &str = "WASI_ADAPTER";
+
+/// What the adapter build reads, relative to `wasip3-prototyping`.
+const ADAPTER_SOURCES: &[&str] = &[
+    "Cargo.lock",
+    "crates/wasi-preview1-component-adapter",
+    "crates/wasi/wit",
+    "crates/wasi/witx",
+];
+
+const ADAPTER_PATCHES: &[&str] = &[
+    r#"patch.crates-io.wasm-encoder.version="0.228.0""#,
+    r#"patch.crates-io.wasm-encoder.git="https://github.com/bytecodealliance/wasm-tools""#,
+    r#"patch.crates-io.wasm-encoder.rev="df50cdf49b41656351d603a8f99bae11c50ea524""#,
+];
+
+#[cfg(feature = "precompile")]
+#[path = "src/engine.rs"]
+mod engine;
+
 fn main() {
     let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
 
-    let guest = env::current_dir().unwrap().parent().unwrap().join("guest");
+    let profile = env::var(GUEST_PROFILE).unwrap_or_else(|_| env::var("PROFILE").unwrap());
+    let target = env::var(GUEST_TARGET).unwrap_or_else(|_| "wasm32-wasip1".to_owned());
+    let target_dir = out_dir.join(&target).join(&profile);
 
-    let status = Command::new("cargo")
-        .current_dir(&guest)
+    let mut adapter = None;
+    let precompiler = Precompiler::new();
+    let mut components = Vec::new();
+    for guest in guests(&manifest_dir) {
+        let name = package_name(&guest);
+        build(&guest, &out_dir, &target, &profile);
+
+        let module =
+            std::fs::read(target_dir.join(format!("{}.wasm", name.replace('-', "_")))).unwrap();
+        let component = match target.as_str() {
+            "wasm32-wasip1" => {
+                let adapter = adapter.get_or_insert_with(|| load_adapter(&out_dir));
+                componentize(&module, adapter)
+            }
+            "wasm32-wasip2" => module,
+            other => panic!("{GUEST_TARGET}={other}: expected `wasm32-wasip1` or `wasm32-wasip2`"),
+        };
+
+        let path = emit(&precompiler, &target_dir, &name, component);
+        track(&guest);
+        let doc = format!("The `{name}` crate, built as `{target}` in `{profile}`.");
+        components.push((name, path, doc));
+    }
+
+    for (name, root, definitions) in compositions(&manifest_dir) {
+        let path_of = |guest: &str| {
+            components
+                .iter()
+                .find(|(component, ..)| component == guest)
+                .map(|(_, path, _)| path.clone())
+                .unwrap_or_else(|| panic!("composition `{name}` uses unknown guest `{guest}`"))
+        };
+        let config = wasm_compose::config::Config {
+            definitions: definitions.iter().map(|guest| path_of(guest)).collect(),
+            ..Default::default()
+        };
+        let component = ComponentComposer::new(&path_of(&root), &config)
+            .compose()
+            .unwrap_or_else(|err| panic!("failed to compose `{name}`: {err:?}"));
+        let path = emit(&precompiler, &target_dir, &name, component);
+        let doc = format!(
+            "`{root}` with its imports satisfied by `{}`.",
+            definitions.join("`, `")
+        );
+        components.push((name, path, doc));
+    }
+
+    let mut generated = String::new();
+    for (name, path, doc) in &components {
+        generated += &format!(
+            "/// {doc}\n\
+             pub const {}: Guest = Guest {{\n\
+             \x20   name: {name:?},\n\
+             \x20   path: {path:?},\n\
+             \x20   wasm: include_bytes!({path:?}),\n\
+             \x20   precompiled: include_bytes!({:?}),\n\
+             }};\n",
+            const_name(name),
+            path.with_extension("cwasm").display().to_string(),
+            path = path.display().to_string(),
+        );
+    }
+    generated += &format!(
+        "/// What [`compatibility`] returns for the engine that precompiled the\n\
+         /// guests, or `None` if they were not precompiled.\n\
+         pub const PRECOMPILED_FOR: Option<u64> = {:?};\n",
+        precompiler.compatibility(),
+    );
+    generated +=
+        "/// Every guest listed in `package.metadata.guests`.\npub const COMPONENTS: &[Guest] = &[";
+    for (name, ..) in &components {
+        generated += &format!("{}, ", const_name(name));
+    }
+    generated += "];\n";
+    std::fs::write(out_dir.join("components.rs"), generated).expect("write components.rs");
+
+    println!(
+        "cargo:rerun-if-changed={}",
+        manifest_dir.join("Cargo.toml").display()
+    );
+    println!(
+        "cargo:rerun-if-changed={}",
+        manifest_dir.join("src/engine.rs").display()
+    );
+    println!("cargo:rerun-if-env-changed={GUEST_PROFILE}");
+    println!("cargo:rerun-if-env-changed={GUEST_TARGET}");
+}
+
+/// Precompiles components for the host's target, with an engine configured
+/// like the host's by `engine::configure`. Without the `precompile` feature
+/// the precompiled form is left empty, and components are compiled at
+/// runtime.
+struct Precompiler {
+    #[cfg(feature = "precompile")]
+    engine: wasmtime::Engine,
+}
+
+impl Precompiler {
+    #[cfg(feature = "precompile")]
+    fn new() -> Self {
+        let mut config = wasmtime::Config::new();
+        engine::configure(&mut config);
+        config
+            .target(&env::var("TARGET").unwrap())
+            .expect("wasmtime supports the host target");
+        let engine = wasmtime::Engine::new(&config).expect("engine for precompiling guests");
+        Self { engine }
+    }
+
+    #[cfg(not(feature = "precompile"))]
+    fn new() -> Self {
+        Self {}
+    }
+
+    #[cfg(feature = "precompile")]
+    fn precompile(&self, name: &str, component: &[u8]) -> Vec<u8> {
+        self.engine
+            .precompile_component(component)
+            .unwrap_or_else(|err| panic!("failed to precompile `{name}`: {err:?}"))
+    }
+
+    #[cfg(not(feature = "precompile"))]
+    fn precompile(&self, _: &str, _: &[u8]) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// What `components::compatibility` must return for an engine to load
+    /// the precompiled components.
+    #[cfg(feature = "precompile")]
+    fn compatibility(&self) -> Option<u64> {
+        use std::hash::Hash;
+        use std::hash::Hasher;
+
+        let mut hasher = std::hash::DefaultHasher::new();
+        self.engine
+            .precompile_compatibility_hash()
+            .hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    #[cfg(not(feature = "precompile"))]
+    fn compatibility(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Validates `component`, then writes it and its precompiled form to
+/// `target_dir`.
+fn emit(precompiler: &Precompiler, target_dir: &Path, name: &str, component: Vec<u8>) -> PathBuf {
+    Validator::new_with_features(WasmFeatures::all())
+        .validate_all(&component)
+        .unwrap_or_else(|err| panic!("component `{name}` should validate: {err}"));
+
+    let path = target_dir.join(format!("{name}.component.wasm"));
+    let precompiled = precompiler.precompile(name, &component);
+    std::fs::write(&path, component).expect("write component to disk");
+    std::fs::write(path.with_extension("cwasm"), precompiled)
+        .expect("write precompiled component to disk");
+    path
+}
+
+/// The guest crates in `package.metadata.guests`, relative to the host.
+fn guests(manifest_dir: &Path) -> Vec<PathBuf> {
+    let manifest = read_manifest(manifest_dir);
+    let guests = manifest
+        .get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("guests"))
+        .and_then(|guests| guests.as_array())
+        .expect("`package.metadata.guests` should list the guest crates");
+    guests
+        .iter()
+        .map(|guest| {
+            let guest = guest.as_str().expect("guest paths should be strings");
+            manifest_dir.join(guest)
+        })
+        .collect()
+}
+
+/// The entries of `package.metadata.compositions`: a name, the root guest, and
+/// the guests whose exports satisfy the root's imports.
+fn compositions(manifest_dir: &Path) -> Vec<(String, String, Vec<String>)> {
+    let manifest = read_manifest(manifest_dir);
+    let Some(compositions) = manifest
+        .get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("compositions"))
+        .and_then(|compositions| compositions.as_table())
+    else {
+        return Vec::new();
+    };
+    compositions
+        .iter()
+        .map(|(name, composition)| {
+            let root = composition
+                .get("root")
+                .and_then(|root| root.as_str())
+                .unwrap_or_else(|| panic!("composition `{name}` has no `root`"));
+            let definitions = composition
+                .get("definitions")
+                .and_then(|definitions| definitions.as_array())
+                .into_iter()
+                .flatten()
+                .map(|definition| {
+                    let definition = definition.as_str().expect("guest names should be strings");
+                    definition.to_owned()
+                })
+                .collect();
+            (name.clone(), root.to_owned(), definitions)
+        })
+        .collect()
+}
+
+fn package_name(guest: &Path) -> String {
+    read_manifest(guest)
+        .get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(|name| name.as_str())
+        .unwrap_or_else(|| panic!("{} has no package name", guest.display()))
+        .to_owned()
+}
+
+fn const_name(name: &str) -> String {
+    name.replace('-', "_").to_uppercase()
+}
+
+fn read_manifest(dir: &Path) -> toml::Table {
+    let path = dir.join("Cargo.toml");
+    std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("failed to read {}: {err}", path.display()))
+        .parse()
+        .unwrap_or_else(|err| panic!("failed to parse {}: {err}", path.display()))
+}
+
+/// Reruns the build script when the guest's sources change. Listing them,
+/// rather than the whole crate, keeps the guest's own `target` out of it.
+fn track(guest: &Path) {
+    for input in [
+        "src",
+        "build.rs",
+        "Cargo.toml",
+        "Cargo.lock",
+        "interface.wit",
+        "wit",
+    ] {
+        let path = guest.join(input);
+        if path.exists() {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+}
+
+fn build(guest: &Path, out_dir: &Path, target: &str, profile: &str) {
+    let mut command = Command::new("cargo");
+    command
+        .current_dir(guest)
         .arg("build")
         .arg("--target")
-        .arg("wasm32-wasip1")
-        .env("CARGO_TARGET_DIR", &out_dir)
-        .status()
-        .unwrap();
+        .arg(target)
+        .env("CARGO_TARGET_DIR", out_dir);
+    match profile {
+        "debug" => {}
+        "release" => {
+            command.arg("--release");
+        }
+        other => panic!("{GUEST_PROFILE}={other}: expected `debug` or `release`"),
+    }
+    let status = command.status().unwrap();
 
     if !status.success() {
         panic!("Failed to build {}", guest.display());
     }
+}
+
+/// Loads the preview1 adapter from [`WASI_ADAPTER`], or from a cache keyed by
+/// the hash of its sources, building it only on a cache miss.
+fn load_adapter(out_dir: &Path) -> Vec<u8> {
+    println!("cargo:rerun-if-env-changed={WASI_ADAPTER}");
+    if let Some(path) = env::var_os(WASI_ADAPTER) {
+        let path = PathBuf::from(path);
+        println!("cargo:rerun-if-changed={}", path.display());
+        return std::fs::read(&path)
+            .unwrap_or_else(|err| panic!("{WASI_ADAPTER}={}: {err}", path.display()));
+    }
 
     let wasip3_prototyping = env::current_dir()
         .unwrap()
@@ -30,6 +343,18 @@ fn main() {
         .unwrap()
         .join("wasip3-prototyping");
 
+    // `OUT_DIR` is `<target dir>/<profile>/build/host-<hash>/out`; keeping the
+    // cache next to the profiles shares it between them and across rebuilds
+    // of the host.
+    let cache_dir = out_dir.ancestors().nth(4).unwrap().join("wasi-adapter");
+    let cached = cache_dir.join(format!(
+        "wasi_snapshot_preview1-{}.wasm",
+        adapter_hash(&wasip3_prototyping)
+    ));
+    if let Ok(adapter) = std::fs::read(&cached) {
+        return adapter;
+    }
+
     let status = Command::new("cargo")
         .current_dir(&wasip3_prototyping)
         .arg("build")
@@ -38,13 +363,12 @@ fn main() {
         .arg("--target")
         .arg("wasm32-unknown-unknown")
         // Ensure we use the same version of `wasm-encoder`
-        .arg("--config")
-        .arg(r#"patch.crates-io.wasm-encoder.version="0.228.0""#)
-        .arg("--config")
-        .arg(r#"patch.crates-io.wasm-encoder.git="https://github.com/bytecodealliance/wasm-tools""#)
-        .arg("--config")
-        .arg(r#"patch.crates-io.wasm-encoder.rev="df50cdf49b41656351d603a8f99bae11c50ea524""#)
-        .env("CARGO_TARGET_DIR", &out_dir)
+        .args(
+            ADAPTER_PATCHES
+                .iter()
+                .flat_map(|patch| ["--config", *patch]),
+        )
+        .env("CARGO_TARGET_DIR", &cache_dir)
         .status()
         .unwrap();
 
@@ -52,43 +376,65 @@ fn main() {
         panic!("Failed to build {}", wasip3_prototyping.display());
     }
 
-    let module = std::fs::read(
-        out_dir
-            .join("wasm32-wasip1")
-            .join("debug")
-            .join("guest.wasm"),
-    )
-    .unwrap();
-
     let adapter = std::fs::read(
-        out_dir
+        cache_dir
             .join("wasm32-unknown-unknown")
             .join("debug")
             .join("wasi_snapshot_preview1.wasm"),
     )
     .unwrap();
+    std::fs::write(&cached, &adapter).expect("write adapter to the cache");
+    adapter
+}
+
+/// Hashes everything the adapter build reads, along with the patches passed
+/// to it.
+fn adapter_hash(wasip3_prototyping: &Path) -> String {
+    let mut hasher = Sha256::new();
+    for patch in ADAPTER_PATCHES {
+        hasher.update(patch.as_bytes());
+    }
+    for source in ADAPTER_SOURCES {
+        let path = wasip3_prototyping.join(source);
+        if path.exists() {
+            println!("cargo:rerun-if-changed={}", path.display());
+            hash_path(&mut hasher, wasip3_prototyping, &path);
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
 
-    let component = ComponentEncoder::default()
-        .module(module.as_slice())
+fn hash_path(hasher: &mut Sha256, root: &Path, path: &Path) {
+    if path.is_dir() {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| !path.ends_with("target"))
+            .collect();
+        entries.sort();
+        for entry in entries {
+            hash_path(hasher, root, &entry);
+        }
+    } else {
+        let relative = path.strip_prefix(root).unwrap();
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update(std::fs::read(path).unwrap());
+    }
+}
+
+/// Turns a `wasm32-wasip1` core module into a component with the preview1
+/// adapter.
+fn componentize(module: &[u8], adapter: &[u8]) -> Vec<u8> {
+    ComponentEncoder::default()
+        .module(module)
         .unwrap()
         .validate(false)
-        .adapter("wasi_snapshot_preview1", &adapter)
+        .adapter("wasi_snapshot_preview1", adapter)
         .unwrap()
         .encode()
-        .expect("module can be translated to a component");
-
-    Validator::new_with_features(WasmFeatures::all())
-        .validate_all(&component)
-        .expect("component output should validate");
-
-    std::fs::write(
-        &out_dir
-            .join("wasm32-wasip1")
-            .join("debug")
-            .join("guest.component.wasm"),
-        component,
-    )
-    .expect("write component to disk");
-
-    println!("cargo:rerun-if-changed={}", guest.display());
+        .expect("module can be translated to a component")
 }
@@ -0,0 +1,27 @@
+//! Runs the guest as a Unix filter, turning CSV bids on stdin into JSON lines
+//! on stdout:
+//!
+//! ```text
+//! cargo run --bin filter < data/bids.csv > bids.jsonl
+//! ```
+
+use std::process::ExitCode;
+
+use host::profiling::Profiling;
+use host::Options;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<ExitCode> {
+    host::telemetry::install();
+    let (instance, mut store, _) = host::init_with(Options {
+        profiling: Profiling::from_env()?,
+        ..Options::default()
+    })
+    .await;
+    let result = host::command::run(&instance, &mut store).await;
+    host::telemetry::shutdown();
+    match result? {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        Err(()) => Ok(ExitCode::FAILURE),
+    }
+}
@@ -0,0 +1,23 @@
+//! Running the guest as a `wasi:cli/run` command.
+
+use anyhow::Context;
+use anyhow::Result;
+use wasmtime::component::Instance;
+use wasmtime::component::TypedFunc;
+use wasmtime::Store;
+
+use crate::Host;
+
+/// Calls the guest's `wasi:cli/run#run` export. The outer error is a trap,
+/// the inner one the command's own failure status.
+pub async fn run(instance: &Instance, store: &mut Store<Host>) -> Result<Result<(), ()>> {
+    let run_export = instance
+        .get_export(&mut *store, None, "wasi:cli/run@0.3.0")
+        .context("component does not export wasi:cli/run")?;
+    let export = instance
+        .get_export(&mut *store, Some(&run_export), "run")
+        .context("wasi:cli/run has no `run` function")?;
+    let func: TypedFunc<(), (Result<(), ()>,)> = instance.get_typed_func(&mut *store, export)?;
+    let (result,) = crate::call(store, "wasi:cli/run#run", &func, ()).await?;
+    Ok(result)
+}
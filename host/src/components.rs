@@ -0,0 +1,80 @@
+//! The guest components `build.rs` builds alongside the host, one per crate
+//! listed in `package.metadata.guests`.
+//!
+//! Each component is embedded in the host binary as built and, with the
+//! default `precompile` feature, precompiled with the settings in
+//! [`crate::engine`], so the binary does not depend on `OUT_DIR` and usually
+//! skips compilation at startup.
+
+use std::hash::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+
+use anyhow::Result;
+use wasmtime::component::Component;
+use wasmtime::Engine;
+
+/// A componentized guest crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guest {
+    /// The crate's package name.
+    pub name: &'static str,
+    /// Where the component was written at build time.
+    pub path: &'static str,
+    /// The component itself.
+    pub wasm: &'static [u8],
+    /// The output of `Engine::precompile_component` for [`Guest::wasm`], or
+    /// empty without the `precompile` feature.
+    pub precompiled: &'static [u8],
+}
+
+include!(concat!(env!("OUT_DIR"), "/components.rs"));
+
+/// Looks up a guest by its package name.
+pub fn get(name: &str) -> Option<&'static Guest> {
+    COMPONENTS.iter().find(|guest| guest.name == name)
+}
+
+/// Hashes what `engine` has to agree on with the engine that precompiled a
+/// component for it to load, see [`PRECOMPILED_FOR`].
+pub fn compatibility(engine: &Engine) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Guest {
+    /// Loads the precompiled component, or compiles it if there is none for
+    /// an engine configured like `engine`, e.g. because it runs with fuel or
+    /// profiling.
+    pub fn load(&self, engine: &Engine) -> Result<Component> {
+        match self.deserialize(engine) {
+            Ok(Some(component)) => Ok(component),
+            Ok(None) => {
+                tracing::debug!(
+                    guest = self.name,
+                    "compiling, not precompiled for this engine"
+                );
+                Component::new(engine, self.wasm)
+            }
+            Err(err) => {
+                tracing::warn!(
+                    guest = self.name,
+                    "compiling, precompiled component rejected: {err:#}"
+                );
+                Component::new(engine, self.wasm)
+            }
+        }
+    }
+
+    /// The precompiled component, or `None` if it was not precompiled for an
+    /// engine configured like `engine`.
+    pub fn deserialize(&self, engine: &Engine) -> Result<Option<Component>> {
+        if self.precompiled.is_empty() || PRECOMPILED_FOR != Some(compatibility(engine)) {
+            return Ok(None);
+        }
+        // SAFETY: `precompiled` was produced by `Engine::precompile_component`
+        // in `build.rs`, with the same wasmtime, and is embedded read-only.
+        unsafe { Component::deserialize(engine, self.precompiled) }.map(Some)
+    }
+}
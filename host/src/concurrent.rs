@@ -0,0 +1,113 @@
+//! Many export calls in flight on one instance at a time.
+//!
+//! [`crate::call`] waits for each call before the next can start. [`Calls`]
+//! instead starts calls with `call_concurrent`, so the guest interleaves them
+//! at its `await` points, and hands results back as the calls finish, each
+//! tagged with the index it was started under.
+//!
+//! Each call is timed and profiled like one made through [`crate::call`].
+//! While calls overlap, the fuel each is charged includes what the others
+//! consumed meanwhile, and a per-call guest profile is cut short by the next
+//! call starting.
+
+use anyhow::Result;
+use wasmtime::component::ComponentNamedList;
+use wasmtime::component::Lift;
+use wasmtime::component::Lower;
+use wasmtime::component::PromisesUnordered;
+use wasmtime::component::TypedFunc;
+use wasmtime::Store;
+
+use crate::metrics::CallTimer;
+use crate::Host;
+
+/// Calls of functions returning `Results`, started on one store.
+pub struct Calls<Results> {
+    pending: PromisesUnordered<(usize, Results)>,
+    /// Timers of the calls that have not finished, by index.
+    timers: Vec<Option<CallTimer>>,
+    finished: usize,
+}
+
+impl<Results> Default for Calls<Results>
+where
+    Results: Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self {
+            pending: PromisesUnordered::new(),
+            timers: Vec::new(),
+            finished: 0,
+        }
+    }
+}
+
+impl<Results> Calls<Results>
+where
+    Results: ComponentNamedList + Lift + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts calling `func` without waiting for it, and returns the index
+    /// its results will be tagged with. The call is recorded under `export`.
+    pub fn push<Params>(
+        &mut self,
+        store: &mut Store<Host>,
+        export: &str,
+        func: &TypedFunc<Params, Results>,
+        params: Params,
+    ) -> Result<usize>
+    where
+        Params: ComponentNamedList + Lower + Send + Sync + 'static,
+    {
+        let index = self.timers.len();
+        let timer = CallTimer::start(store, export);
+        let promise = match func.call_concurrent(&mut *store, params) {
+            Ok(promise) => promise,
+            Err(err) => {
+                timer.finish(store);
+                return Err(err);
+            }
+        };
+        self.pending
+            .push(promise.map(move |results| (index, results)));
+        self.timers.push(Some(timer));
+        Ok(index)
+    }
+
+    /// Calls that have been started but not yet returned by [`Calls::next`].
+    pub fn len(&self) -> usize {
+        self.timers.len() - self.finished
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Runs the store until the next call finishes, and returns its index and
+    /// results, or `None` once every call has been returned.
+    pub async fn next(&mut self, store: &mut Store<Host>) -> Result<Option<(usize, Results)>> {
+        let next = self.pending.next(&mut *store).await?;
+        if let Some((index, _)) = &next {
+            if let Some(timer) = self.timers[*index].take() {
+                timer.finish(store);
+            }
+            tracing::debug!(index, "call finished");
+            self.finished += 1;
+        }
+        Ok(next)
+    }
+
+    /// Waits for the calls not yet returned by [`Calls::next`], and returns
+    /// their indices and results, ordered by index.
+    pub async fn join(mut self, store: &mut Store<Host>) -> Result<Vec<(usize, Results)>> {
+        let mut results = Vec::with_capacity(self.len());
+        while let Some(next) = self.next(store).await? {
+            results.push(next);
+        }
+        results.sort_by_key(|(index, _)| *index);
+        Ok(results)
+    }
+}
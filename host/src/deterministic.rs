@@ -0,0 +1,280 @@
+//! Virtual clocks and seeded randomness for reproducible guest runs.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use rand_chacha::rand_core::RngCore;
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use tokio::sync::oneshot;
+use tokio::sync::Notify;
+use wasmtime::component::Accessor;
+use wasmtime::component::Linker;
+use wasmtime_wasi::p3::clocks::WasiClocksCtx;
+use wasmtime_wasi::p3::random::WasiRandomCtx;
+use wasmtime_wasi::HostMonotonicClock;
+use wasmtime_wasi::HostWallClock;
+use wasmtime_wasi::WasiCtxBuilder;
+
+use crate::telemetry;
+use crate::Host;
+
+/// Environment variable that [`Deterministic::from_env`] reads its seed from.
+pub const SEED_VAR: &str = "HOST_SEED";
+
+/// Settings for a reproducible run: both clocks start at `epoch` and only
+/// move when the host advances them, and all randomness derives from `seed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deterministic {
+    pub seed: u64,
+    /// Initial wall-clock time, as a duration since the Unix epoch.
+    pub epoch: Duration,
+}
+
+impl Deterministic {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            epoch: Duration::from_secs(1_700_000_000),
+        }
+    }
+
+    /// Uses the seed in `HOST_SEED`, or picks a fresh one. The seed is logged
+    /// either way so a failing run can be replayed with `HOST_SEED=<seed>`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let seed = match std::env::var(SEED_VAR) {
+            Ok(seed) => seed
+                .parse()
+                .with_context(|| format!("{SEED_VAR}={seed}: expected a u64"))?,
+            Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+        };
+        tracing::info!(seed, "deterministic run, replay with {SEED_VAR}={seed}");
+        Ok(Self::new(seed))
+    }
+
+    pub fn clock(&self) -> VirtualClock {
+        VirtualClock::new(self.epoch)
+    }
+
+    /// Clocks for the preview 3 interfaces, driven by `clock`.
+    pub fn clocks(&self, clock: &VirtualClock) -> WasiClocksCtx {
+        WasiClocksCtx {
+            wall_clock: Box::new(clock.clone()),
+            monotonic_clock: Box::new(clock.clone()),
+        }
+    }
+
+    /// Randomness for the preview 3 interfaces.
+    pub fn random(&self) -> WasiRandomCtx {
+        let mut seeds = self.rng(0);
+        WasiRandomCtx {
+            random: Box::new(self.rng(1)),
+            insecure_random: Box::new(self.rng(2)),
+            insecure_random_seed: u128::from(seeds.next_u64()) << 64 | u128::from(seeds.next_u64()),
+        }
+    }
+
+    /// Applies the same clocks and randomness to a preview 2 context, which is
+    /// what the guest's standard library talks to through the adapter.
+    pub fn configure(&self, clock: &VirtualClock, builder: &mut WasiCtxBuilder) {
+        let mut seeds = self.rng(3);
+        builder
+            .wall_clock(clock.clone())
+            .monotonic_clock(clock.clone())
+            .secure_random(self.rng(4))
+            .insecure_random(self.rng(5))
+            .insecure_random_seed(
+                u128::from(seeds.next_u64()) << 64 | u128::from(seeds.next_u64()),
+            );
+    }
+
+    fn rng(&self, stream: u64) -> ChaCha8Rng {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        rng.set_stream(stream);
+        rng
+    }
+}
+
+/// A monotonic and wall clock pair which only moves when [`advance`]d.
+///
+/// Clones share the same time, so the host keeps one handle while the WASI
+/// contexts hold the others. Timers registered with [`sleep`] and
+/// [`sleep_until`] fire from `advance`, in deadline order.
+///
+/// [`advance`]: VirtualClock::advance
+/// [`sleep`]: VirtualClock::sleep
+/// [`sleep_until`]: VirtualClock::sleep_until
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    state: Arc<Mutex<ClockState>>,
+    /// Notified whenever a timer is registered.
+    registered: Arc<Notify>,
+}
+
+#[derive(Debug)]
+struct ClockState {
+    /// Nanoseconds since the clock was created.
+    monotonic: u64,
+    /// Time since the Unix epoch.
+    wall: Duration,
+    /// Pending timers, kept sorted by deadline and then registration order.
+    timers: Vec<Timer>,
+    next_timer: u64,
+}
+
+#[derive(Debug)]
+struct Timer {
+    deadline: u64,
+    id: u64,
+    wake: oneshot::Sender<()>,
+}
+
+impl VirtualClock {
+    pub fn new(epoch: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ClockState {
+                monotonic: 0,
+                wall: epoch,
+                timers: Vec::new(),
+                next_timer: 0,
+            })),
+            registered: Arc::default(),
+        }
+    }
+
+    pub fn monotonic(&self) -> u64 {
+        self.state.lock().unwrap().monotonic
+    }
+
+    pub fn wall(&self) -> Duration {
+        self.state.lock().unwrap().wall
+    }
+
+    /// Moves both clocks forward by `by`, firing every timer whose deadline
+    /// is reached along the way, in deadline order.
+    pub fn advance(&self, by: Duration) {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            let target = state.monotonic + u64::try_from(by.as_nanos()).unwrap();
+            let due = state.timers.partition_point(|t| t.deadline <= target);
+            state.monotonic = target;
+            state.wall += by;
+            state.timers.drain(..due).collect::<Vec<_>>()
+        };
+        for timer in due {
+            let _ = timer.wake.send(());
+        }
+    }
+
+    /// Number of timers that have not fired yet.
+    pub fn pending_timers(&self) -> usize {
+        self.state.lock().unwrap().timers.len()
+    }
+
+    /// Completes once at least `n` timers are pending, e.g. to advance the
+    /// clock only when the guest is waiting on it.
+    pub async fn timers_pending(&self, n: usize) {
+        loop {
+            let registered = self.registered.notified();
+            tokio::pin!(registered);
+            // Registered before checking, so a timer added in between is not
+            // missed.
+            registered.as_mut().enable();
+            if self.pending_timers() >= n {
+                return;
+            }
+            registered.await;
+        }
+    }
+
+    /// Completes once the clock has been advanced by at least `duration`.
+    pub fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send + 'static {
+        let deadline = self.monotonic() + u64::try_from(duration.as_nanos()).unwrap();
+        self.sleep_until(deadline)
+    }
+
+    /// Completes once the monotonic clock reaches `deadline` nanoseconds.
+    pub fn sleep_until(&self, deadline: u64) -> impl Future<Output = ()> + Send + 'static {
+        let mut state = self.state.lock().unwrap();
+        let (wake, woken) = oneshot::channel();
+        if deadline <= state.monotonic {
+            let _ = wake.send(());
+        } else {
+            let id = state.next_timer;
+            state.next_timer += 1;
+            let index = state
+                .timers
+                .partition_point(|t| (t.deadline, t.id) < (deadline, id));
+            state.timers.insert(index, Timer { deadline, id, wake });
+            self.registered.notify_waiters();
+        }
+        async move {
+            let _ = woken.await;
+        }
+    }
+}
+
+impl HostMonotonicClock for VirtualClock {
+    fn resolution(&self) -> u64 {
+        1
+    }
+
+    fn now(&self) -> u64 {
+        self.monotonic()
+    }
+}
+
+impl HostWallClock for VirtualClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self) -> Duration {
+        self.wall()
+    }
+}
+
+/// Replaces `wasi:clocks/monotonic-clock`'s `wait-for` and `wait-until` with
+/// versions driven by the instance's [`VirtualClock`].
+///
+/// Must be called after the regular clocks have been added to `linker`, and
+/// only for instances with a virtual clock.
+pub fn add_timers_to_linker(linker: &mut Linker<Host>) -> anyhow::Result<()> {
+    linker.allow_shadowing(true);
+    let mut clocks = linker.instance("wasi:clocks/monotonic-clock@0.3.0")?;
+    clocks.func_wrap_concurrent(
+        "wait-for",
+        |accessor: &mut Accessor<Host>, (duration,): (u64,)| {
+            telemetry::import(accessor, "wasi:clocks/monotonic-clock@0.3.0#wait-for");
+            let clock = clock(accessor);
+            Box::pin(async move {
+                clock?.sleep(Duration::from_nanos(duration)).await;
+                Ok(())
+            })
+        },
+    )?;
+    clocks.func_wrap_concurrent(
+        "wait-until",
+        |accessor: &mut Accessor<Host>, (when,): (u64,)| {
+            telemetry::import(accessor, "wasi:clocks/monotonic-clock@0.3.0#wait-until");
+            let clock = clock(accessor);
+            Box::pin(async move {
+                clock?.sleep_until(when).await;
+                Ok(())
+            })
+        },
+    )?;
+    linker.allow_shadowing(false);
+    Ok(())
+}
+
+fn clock(accessor: &mut Accessor<Host>) -> anyhow::Result<VirtualClock> {
+    accessor
+        .with(|mut view| view.get().clock.clone())
+        .ok_or_else(|| anyhow::anyhow!("virtual timers need a virtual clock"))
+}
@@ -0,0 +1,254 @@
+//! Calling exports by name with dynamically typed [`Val`]s.
+//!
+//! Everything is driven by the types the component reports for its exports,
+//! so this works for components the host was not compiled against. `future`
+//! and `stream` parameters are backed by host futures and streams created on
+//! the fly, and `future` and `stream` results are drained before
+//! [`Dynamic::call`] returns. Their payloads are moved as [`Val`]s too, so any
+//! payload type works, e.g. `future<result<_, error>>` or `stream<record>`.
+//! Results that are tuples of futures and streams, such as `read-file`'s
+//! `tuple<stream<u8>, future<..>>`, are drained field by field, in order.
+//! Exports with futures or streams nested anywhere else in their results are
+//! refused before they are called, rather than handing back handles nothing
+//! could drain.
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use tracing::Instrument;
+use wasmtime::component::Func;
+use wasmtime::component::HostFuture;
+use wasmtime::component::HostStream;
+use wasmtime::component::Instance;
+use wasmtime::component::Type;
+use wasmtime::component::Val;
+use wasmtime::component::VecBuffer;
+use wasmtime::Store;
+
+use crate::metrics::CallTimer;
+use crate::streams;
+use crate::Host;
+
+/// How many stream items are written per chunk.
+const CHUNK: usize = 64 * 1024;
+
+/// A parameter to [`Dynamic::call`].
+#[derive(Debug, Clone)]
+pub enum Arg {
+    /// Passed as is.
+    Value(Val),
+    /// The value a `future<T>` parameter resolves to.
+    Future(Val),
+    /// The items written to a `stream<T>` parameter before it is closed.
+    Stream(Vec<Val>),
+}
+
+/// A result of [`Dynamic::call`], with futures and streams already drained.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ret {
+    Value(Val),
+    /// What a `future<T>` result resolved to, or `None` if it was closed
+    /// without a value.
+    Future(Option<Val>),
+    /// Every item a `stream<T>` result produced.
+    Stream(Vec<Val>),
+    /// The fields of a `tuple` result with futures or streams among them,
+    /// each drained like a result of its own.
+    Tuple(Vec<Ret>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub params: Vec<(String, Type)>,
+    pub results: Vec<Type>,
+}
+
+/// Calls exports of one instance by name, e.g. `pkg:component/intf#test`.
+#[derive(Clone, Copy)]
+pub struct Dynamic {
+    instance: Instance,
+}
+
+impl Dynamic {
+    pub fn new(instance: Instance) -> Self {
+        Self { instance }
+    }
+
+    /// Looks up `interface#name`-style names, or plain top-level names.
+    pub fn func(&self, store: &mut Store<Host>, export: &str) -> Result<Func> {
+        let index = match export.split_once('#') {
+            Some((interface, name)) => {
+                let interface = self
+                    .instance
+                    .get_export(&mut *store, None, interface)
+                    .with_context(|| format!("no exported interface `{interface}`"))?;
+                self.instance
+                    .get_export(&mut *store, Some(&interface), name)
+            }
+            None => self.instance.get_export(&mut *store, None, export),
+        };
+        let index = index.with_context(|| format!("no export `{export}`"))?;
+        self.instance
+            .get_func(&mut *store, index)
+            .with_context(|| format!("`{export}` is not a function"))
+    }
+
+    pub fn signature(&self, store: &mut Store<Host>, export: &str) -> Result<Signature> {
+        let func = self.func(store, export)?;
+        Ok(Signature {
+            params: func.params(&*store).into_vec(),
+            results: func.results(&*store).into_vec(),
+        })
+    }
+
+    pub async fn call(
+        &self,
+        store: &mut Store<Host>,
+        export: &str,
+        args: Vec<Arg>,
+    ) -> Result<Vec<Ret>> {
+        self.call_inner(store, export, args)
+            .instrument(tracing::info_span!("call", export))
+            .await
+    }
+
+    async fn call_inner(
+        &self,
+        store: &mut Store<Host>,
+        export: &str,
+        args: Vec<Arg>,
+    ) -> Result<Vec<Ret>> {
+        let func = self.func(store, export)?;
+        let params = func.params(&*store);
+        ensure!(
+            params.len() == args.len(),
+            "`{export}` takes {} arguments but {} were given",
+            params.len(),
+            args.len()
+        );
+
+        let mut values = Vec::with_capacity(args.len());
+        for ((name, ty), arg) in params.iter().zip(args) {
+            let value = self
+                .lower(store, ty, arg)
+                .with_context(|| format!("invalid argument `{name}`"))?;
+            values.push(value);
+        }
+
+        let types = func.results(&*store);
+        for ty in types.iter() {
+            drainable(ty).with_context(|| format!("`{export}` cannot be called dynamically"))?;
+        }
+        let mut results = vec![Val::Bool(false); types.len()];
+        let timer = CallTimer::start(store, export);
+        let called = async {
+            func.call_async(&mut *store, &values, &mut results)
+                .instrument(tracing::debug_span!("call_async"))
+                .await?;
+            func.post_return_async(&mut *store)
+                .instrument(tracing::debug_span!("post_return_async"))
+                .await
+        }
+        .await;
+        timer.finish(store);
+        called?;
+
+        let mut rets = Vec::with_capacity(results.len());
+        for (ty, value) in types.iter().zip(results) {
+            rets.push(lift(store, ty, value).await?);
+        }
+        Ok(rets)
+    }
+
+    fn lower(&self, store: &mut Store<Host>, ty: &Type, arg: Arg) -> Result<Val> {
+        match (ty, arg) {
+            (Type::Future(future), Arg::Future(value)) => {
+                future
+                    .ty()
+                    .context("futures without a payload are unsupported")?;
+                let (tx, rx) = self.instance.future::<Val>(&mut *store)?;
+                streams::spawn_future_writer(tx, value);
+                Ok(HostFuture::from(rx).into_val())
+            }
+            (Type::Stream(stream), Arg::Stream(items)) => {
+                stream
+                    .ty()
+                    .context("streams without a payload are unsupported")?;
+                let (tx, rx) = self
+                    .instance
+                    .stream::<Val, VecBuffer<Val>, Vec<Val>, _, _>(&mut *store)?;
+                streams::spawn_writer(tx, items, CHUNK);
+                Ok(HostStream::from(rx).into_val())
+            }
+            (Type::Future(_), _) => bail!("expected a future"),
+            (Type::Stream(_), _) => bail!("expected a stream"),
+            (_, Arg::Value(value)) => Ok(value),
+            (_, _) => bail!("expected a plain value"),
+        }
+    }
+}
+
+/// Whether `err`, from [`Dynamic::call`], is a trap. An instance that trapped
+/// cannot be entered again, so every later call on it fails too.
+pub fn is_trap(err: &anyhow::Error) -> bool {
+    err.is::<wasmtime::Trap>()
+}
+
+async fn lift(store: &mut Store<Host>, ty: &Type, value: Val) -> Result<Ret> {
+    match ty {
+        Type::Future(future) => {
+            future
+                .ty()
+                .context("futures without a payload are unsupported")?;
+            let future = HostFuture::<Val>::from_val(&mut *store, &value)?;
+            Ok(Ret::Future(streams::read_future(store, future).await?))
+        }
+        Type::Stream(stream) => {
+            stream
+                .ty()
+                .context("streams without a payload are unsupported")?;
+            let stream = HostStream::<Val>::from_val(&mut *store, &value)?;
+            Ok(Ret::Stream(streams::read_all(store, stream).await?))
+        }
+        Type::Tuple(tuple) if has_handles(ty) => {
+            let Val::Tuple(fields) = value else {
+                bail!("expected a tuple");
+            };
+            let mut rets = Vec::with_capacity(fields.len());
+            for (ty, field) in tuple.types().zip(fields) {
+                rets.push(Box::pin(lift(store, &ty, field)).await?);
+            }
+            Ok(Ret::Tuple(rets))
+        }
+        _ => Ok(Ret::Value(value)),
+    }
+}
+
+/// Fails for a result type [`lift`] could not drain: one with futures or
+/// streams nested somewhere other than at the top or in tuples.
+fn drainable(ty: &Type) -> Result<()> {
+    match ty {
+        Type::Future(_) | Type::Stream(_) => Ok(()),
+        Type::Tuple(tuple) => tuple.types().try_for_each(|ty| drainable(&ty)),
+        ty if has_handles(ty) => {
+            bail!("futures and streams are only drained at the top of a result or in tuples")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether a value of type `ty` can hold a future or stream.
+fn has_handles(ty: &Type) -> bool {
+    let optional = |ty: Option<Type>| ty.as_ref().is_some_and(has_handles);
+    match ty {
+        Type::Future(_) | Type::Stream(_) => true,
+        Type::List(list) => has_handles(&list.ty()),
+        Type::Option(option) => has_handles(&option.ty()),
+        Type::Result(result) => optional(result.ok()) || optional(result.err()),
+        Type::Tuple(tuple) => tuple.types().any(|ty| has_handles(&ty)),
+        Type::Record(record) => record.fields().any(|field| has_handles(&field.ty)),
+        Type::Variant(variant) => variant.cases().any(|case| optional(case.ty)),
+        _ => false,
+    }
+}
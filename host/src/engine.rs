@@ -0,0 +1,11 @@
+//! Engine settings shared with `build.rs`, which precompiles the guests with
+//! them under the `precompile` feature. Anything that changes generated code
+//! has to be set here, or the precompiled components are rejected and compiled
+//! again at runtime.
+
+use wasmtime::Config;
+
+pub fn configure(config: &mut Config) {
+    config.async_support(true);
+    config.wasm_component_model_async(true);
+}
@@ -0,0 +1,107 @@
+//! File trees spelled out in memory, from a map or a tar archive, for the
+//! `data` preopen.
+//!
+//! The tree is written to a temporary directory private to the store, which
+//! is preopened like any other: wasmtime-wasi's filesystem only serves
+//! `cap_std` directories, so an in-memory preopen would mean reimplementing
+//! all of `wasi:filesystem`. That part of the request is descoped; the
+//! fixture still keeps a store's files independent of the working directory
+//! and of other stores.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Result;
+use tempfile::TempDir;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fixture {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads every regular file out of a tar archive.
+    pub fn from_tar(reader: impl Read) -> Result<Self> {
+        let mut fs = Self::new();
+        for entry in tar::Archive::new(reader).entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.into_owned();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            fs.insert(path, contents)?;
+        }
+        Ok(fs)
+    }
+
+    /// Adds a file, replacing any previous contents. `path` must name a file
+    /// below the root: it may not be empty, absolute or contain `..`, and it
+    /// may neither lie below another file nor have files below it.
+    pub fn insert(
+        &mut self,
+        path: impl Into<PathBuf>,
+        contents: impl Into<Vec<u8>>,
+    ) -> Result<&mut Self> {
+        let path = path.into();
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => normalized.push(name),
+                Component::CurDir => {}
+                _ => bail!("invalid fixture path: {}", path.display()),
+            }
+        }
+        if normalized.as_os_str().is_empty() {
+            bail!("invalid fixture path: {path:?}");
+        }
+        if let Some(file) = normalized
+            .ancestors()
+            .skip(1)
+            .find(|parent| self.files.contains_key(*parent))
+        {
+            bail!("{} is below the file {}", path.display(), file.display());
+        }
+        if let Some(below) = self
+            .files
+            .keys()
+            .find(|file| **file != normalized && file.starts_with(&normalized))
+        {
+            bail!("{} is a directory of {}", path.display(), below.display());
+        }
+        self.files.insert(normalized, contents.into());
+        Ok(self)
+    }
+
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&[u8]> {
+        self.files.get(path.as_ref()).map(Vec::as_slice)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = (&Path, &[u8])> {
+        self.files.iter().map(|(p, c)| (p.as_path(), c.as_slice()))
+    }
+
+    /// Writes the tree into a fresh temporary directory, which is removed
+    /// again when the returned handle is dropped. Later changes to the
+    /// fixture do not reach the directory, nor the other way around.
+    pub fn write_to_temp_dir(&self) -> Result<TempDir> {
+        let dir = TempDir::new()?;
+        for (path, contents) in &self.files {
+            let path = dir.path().join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, contents)?;
+        }
+        Ok(dir)
+    }
+}
@@ -0,0 +1,336 @@
+//! Conversion between JSON and component values, guided by WIT types.
+//!
+//! | WIT                     | JSON                                        |
+//! |-------------------------|---------------------------------------------|
+//! | integers, floats, bool  | numbers and booleans                        |
+//! | `char`, `string`        | strings                                     |
+//! | `list<T>`, `tuple<..>`  | arrays                                      |
+//! | `record`                | objects keyed by field name                 |
+//! | `variant`               | `"case"`, or `{"case": payload}`            |
+//! | `enum`                  | `"case"`                                    |
+//! | `flags`                 | arrays of flag names                        |
+//! | `option<T>`             | `null` or the value; `{"some": ..}` when `T` is itself an option |
+//! | `result<T, E>`          | `{"ok": ..}` or `{"err": ..}`, `null` payloads when absent |
+//! | `own<R>`, `borrow<R>`   | `{"resource": id}`, an opaque id from [`Handles`] |
+//!
+//! `future`, `stream` and `error-context` values have no JSON form. Their
+//! contents are passed separately, see [`crate::dynamic::Arg`].
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Number;
+use serde_json::Value;
+use wasmtime::component::ResourceAny;
+use wasmtime::component::Type;
+use wasmtime::component::Val;
+
+/// Gives resources an opaque id so they can leave and re-enter the host as
+/// JSON. Ids are only meaningful to the `Handles` which issued them.
+#[derive(Debug, Default)]
+pub struct Handles {
+    resources: Vec<ResourceAny>,
+}
+
+impl Handles {
+    pub fn insert(&mut self, resource: ResourceAny) -> u64 {
+        if let Some(id) = self.resources.iter().position(|r| *r == resource) {
+            return id as u64;
+        }
+        self.resources.push(resource);
+        self.resources.len() as u64 - 1
+    }
+
+    pub fn get(&self, id: u64) -> Option<ResourceAny> {
+        self.resources.get(usize::try_from(id).ok()?).copied()
+    }
+}
+
+/// Converts `json` to a value of type `ty`.
+pub fn to_val(ty: &Type, json: &Value, handles: &Handles) -> Result<Val> {
+    let mismatch = || anyhow!("expected {}, found {json}", name(ty));
+    Ok(match ty {
+        Type::Bool => Val::Bool(json.as_bool().ok_or_else(mismatch)?),
+        Type::S8 => Val::S8(json.as_i64().ok_or_else(mismatch)?.try_into()?),
+        Type::U8 => Val::U8(json.as_u64().ok_or_else(mismatch)?.try_into()?),
+        Type::S16 => Val::S16(json.as_i64().ok_or_else(mismatch)?.try_into()?),
+        Type::U16 => Val::U16(json.as_u64().ok_or_else(mismatch)?.try_into()?),
+        Type::S32 => Val::S32(json.as_i64().ok_or_else(mismatch)?.try_into()?),
+        Type::U32 => Val::U32(json.as_u64().ok_or_else(mismatch)?.try_into()?),
+        Type::S64 => Val::S64(json.as_i64().ok_or_else(mismatch)?),
+        Type::U64 => Val::U64(json.as_u64().ok_or_else(mismatch)?),
+        Type::Float32 => Val::Float32(json.as_f64().ok_or_else(mismatch)? as f32),
+        Type::Float64 => Val::Float64(json.as_f64().ok_or_else(mismatch)?),
+        Type::Char => {
+            let s = json.as_str().ok_or_else(mismatch)?;
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Val::Char(c),
+                _ => bail!("expected a single character, found {json}"),
+            }
+        }
+        Type::String => Val::String(json.as_str().ok_or_else(mismatch)?.to_owned()),
+        Type::List(list) => {
+            let ty = list.ty();
+            let items = json.as_array().ok_or_else(mismatch)?;
+            Val::List(
+                items
+                    .iter()
+                    .map(|item| to_val(&ty, item, handles))
+                    .collect::<Result<_>>()?,
+            )
+        }
+        Type::Record(record) => {
+            let object = json.as_object().ok_or_else(mismatch)?;
+            let mut fields = Vec::new();
+            for field in record.fields() {
+                let value = object.get(field.name).unwrap_or(&Value::Null);
+                let value = to_val(&field.ty, value, handles)
+                    .with_context(|| format!("in field `{}`", field.name))?;
+                fields.push((field.name.to_owned(), value));
+            }
+            if let Some(unknown) = object
+                .keys()
+                .find(|key| !record.fields().any(|field| field.name == key.as_str()))
+            {
+                bail!("unknown field `{unknown}`");
+            }
+            Val::Record(fields)
+        }
+        Type::Tuple(tuple) => {
+            let items = json.as_array().ok_or_else(mismatch)?;
+            if items.len() != tuple.types().len() {
+                return Err(mismatch());
+            }
+            Val::Tuple(
+                tuple
+                    .types()
+                    .zip(items)
+                    .map(|(ty, item)| to_val(&ty, item, handles))
+                    .collect::<Result<_>>()?,
+            )
+        }
+        Type::Variant(variant) => {
+            let (case, payload) = tagged(json).ok_or_else(mismatch)?;
+            let Some(found) = variant.cases().find(|c| c.name == case) else {
+                bail!("unknown case `{case}`");
+            };
+            let payload = match (found.ty, payload) {
+                (Some(ty), Some(payload)) => Some(Box::new(to_val(&ty, payload, handles)?)),
+                (None, None) => None,
+                (Some(_), None) => bail!("case `{case}` needs a payload"),
+                (None, Some(_)) => bail!("case `{case}` has no payload"),
+            };
+            Val::Variant(case.to_owned(), payload)
+        }
+        Type::Enum(enum_) => {
+            let case = json.as_str().ok_or_else(mismatch)?;
+            if !enum_.names().any(|name| name == case) {
+                bail!("unknown case `{case}`");
+            }
+            Val::Enum(case.to_owned())
+        }
+        Type::Option(option) => {
+            let inner = option.ty();
+            match json {
+                Value::Null => Val::Option(None),
+                json if matches!(inner, Type::Option(_)) => {
+                    let some = json.get("some").ok_or_else(mismatch)?;
+                    Val::Option(Some(Box::new(to_val(&inner, some, handles)?)))
+                }
+                json => Val::Option(Some(Box::new(to_val(&inner, json, handles)?))),
+            }
+        }
+        Type::Result(result) => {
+            let (case, payload) = tagged(json).ok_or_else(mismatch)?;
+            let payload = |ty: Option<Type>| -> Result<Option<Box<Val>>> {
+                match (ty, payload) {
+                    (Some(ty), Some(payload)) => Ok(Some(Box::new(to_val(&ty, payload, handles)?))),
+                    (None, None | Some(Value::Null)) => Ok(None),
+                    (Some(_), None) => bail!("`{case}` needs a payload"),
+                    (None, Some(_)) => bail!("`{case}` has no payload"),
+                }
+            };
+            match case {
+                "ok" => Val::Result(Ok(payload(result.ok())?)),
+                "err" => Val::Result(Err(payload(result.err())?)),
+                _ => return Err(mismatch()),
+            }
+        }
+        Type::Flags(flags) => {
+            let names = json.as_array().ok_or_else(mismatch)?;
+            let mut set = Vec::new();
+            for name in names {
+                let name = name.as_str().ok_or_else(mismatch)?;
+                if !flags.names().any(|flag| flag == name) {
+                    bail!("unknown flag `{name}`");
+                }
+                set.push(name.to_owned());
+            }
+            Val::Flags(set)
+        }
+        Type::Own(_) | Type::Borrow(_) => {
+            let id = json
+                .get("resource")
+                .and_then(Value::as_u64)
+                .ok_or_else(mismatch)?;
+            Val::Resource(
+                handles
+                    .get(id)
+                    .with_context(|| format!("unknown resource {id}"))?,
+            )
+        }
+        Type::Future(_) | Type::Stream(_) | Type::ErrorContext => unsupported(ty)?,
+    })
+}
+
+/// Converts `val`, a value of type `ty`, to JSON.
+pub fn from_val(ty: &Type, val: &Val, handles: &mut Handles) -> Result<Value> {
+    let mismatch = || anyhow!("expected {}, found {val:?}", name(ty));
+    Ok(match (ty, val) {
+        (Type::Bool, Val::Bool(b)) => json!(b),
+        (Type::S8, Val::S8(n)) => json!(n),
+        (Type::U8, Val::U8(n)) => json!(n),
+        (Type::S16, Val::S16(n)) => json!(n),
+        (Type::U16, Val::U16(n)) => json!(n),
+        (Type::S32, Val::S32(n)) => json!(n),
+        (Type::U32, Val::U32(n)) => json!(n),
+        (Type::S64, Val::S64(n)) => json!(n),
+        (Type::U64, Val::U64(n)) => json!(n),
+        (Type::Float32, Val::Float32(n)) => float(f64::from(*n))?,
+        (Type::Float64, Val::Float64(n)) => float(*n)?,
+        (Type::Char, Val::Char(c)) => json!(c.to_string()),
+        (Type::String, Val::String(s)) => json!(s),
+        (Type::List(list), Val::List(items)) => {
+            let ty = list.ty();
+            Value::Array(
+                items
+                    .iter()
+                    .map(|item| from_val(&ty, item, handles))
+                    .collect::<Result<_>>()?,
+            )
+        }
+        (Type::Record(record), Val::Record(values)) => {
+            let mut object = Map::new();
+            for (field, (name, value)) in record.fields().zip(values) {
+                object.insert(name.clone(), from_val(&field.ty, value, handles)?);
+            }
+            Value::Object(object)
+        }
+        (Type::Tuple(tuple), Val::Tuple(values)) => Value::Array(
+            tuple
+                .types()
+                .zip(values)
+                .map(|(ty, value)| from_val(&ty, value, handles))
+                .collect::<Result<_>>()?,
+        ),
+        (Type::Variant(variant), Val::Variant(case, payload)) => {
+            let found = variant
+                .cases()
+                .find(|c| c.name == case)
+                .ok_or_else(mismatch)?;
+            match (found.ty, payload) {
+                (Some(ty), Some(payload)) => tag(case, from_val(&ty, payload, handles)?),
+                (None, None) => json!(case),
+                _ => return Err(mismatch()),
+            }
+        }
+        (Type::Enum(_), Val::Enum(case)) => json!(case),
+        (Type::Option(option), Val::Option(value)) => match value {
+            None => Value::Null,
+            Some(value) => {
+                let inner = option.ty();
+                let value = from_val(&inner, value, handles)?;
+                if matches!(inner, Type::Option(_)) {
+                    tag("some", value)
+                } else {
+                    value
+                }
+            }
+        },
+        (Type::Result(result), Val::Result(value)) => {
+            let (case, ty, payload) = match value {
+                Ok(payload) => ("ok", result.ok(), payload),
+                Err(payload) => ("err", result.err(), payload),
+            };
+            let payload = match (ty, payload) {
+                (Some(ty), Some(payload)) => from_val(&ty, payload, handles)?,
+                (None, None) => Value::Null,
+                _ => return Err(mismatch()),
+            };
+            tag(case, payload)
+        }
+        (Type::Flags(_), Val::Flags(names)) => json!(names),
+        (Type::Own(_) | Type::Borrow(_), Val::Resource(resource)) => {
+            tag("resource", json!(handles.insert(*resource)))
+        }
+        (Type::Future(_) | Type::Stream(_) | Type::ErrorContext, _) => unsupported(ty)?,
+        _ => return Err(mismatch()),
+    })
+}
+
+fn unsupported<T>(ty: &Type) -> Result<T> {
+    bail!(
+        "{} values have no JSON form; pass their contents through `dynamic::Arg` instead",
+        name(ty)
+    )
+}
+
+fn float(n: f64) -> Result<Value> {
+    Number::from_f64(n)
+        .map(Value::Number)
+        .with_context(|| format!("{n} cannot be represented in JSON"))
+}
+
+/// Builds `{"case": payload}`.
+fn tag(case: &str, payload: Value) -> Value {
+    let mut object = Map::new();
+    object.insert(case.to_owned(), payload);
+    Value::Object(object)
+}
+
+/// Splits `"case"` and `{"case": payload}`.
+fn tagged(json: &Value) -> Option<(&str, Option<&Value>)> {
+    match json {
+        Value::String(case) => Some((case, None)),
+        Value::Object(object) if object.len() == 1 => {
+            let (case, payload) = object.iter().next()?;
+            Some((case, Some(payload)))
+        }
+        _ => None,
+    }
+}
+
+fn name(ty: &Type) -> &'static str {
+    match ty {
+        Type::Bool => "bool",
+        Type::S8 => "s8",
+        Type::U8 => "u8",
+        Type::S16 => "s16",
+        Type::U16 => "u16",
+        Type::S32 => "s32",
+        Type::U32 => "u32",
+        Type::S64 => "s64",
+        Type::U64 => "u64",
+        Type::Float32 => "f32",
+        Type::Float64 => "f64",
+        Type::Char => "char",
+        Type::String => "string",
+        Type::List(_) => "list",
+        Type::Record(_) => "record",
+        Type::Tuple(_) => "tuple",
+        Type::Variant(_) => "variant",
+        Type::Enum(_) => "enum",
+        Type::Option(_) => "option",
+        Type::Result(_) => "result",
+        Type::Flags(_) => "flags",
+        Type::Own(_) => "own",
+        Type::Borrow(_) => "borrow",
+        Type::Future(_) => "future",
+        Type::Stream(_) => "stream",
+        Type::ErrorContext => "error-context",
+    }
+}
@@ -0,0 +1,215 @@
+//! `pkg:component/keyvalue`, durable storage for guest state in a
+//! [redb](https://docs.rs/redb) database.
+//!
+//! One database holds any number of namespaces, and each store sees only the
+//! [`Namespace`] it was given through [`Options::keyvalue`], so instances
+//! sharing a database file do not trip over each other's keys. Without one,
+//! the store gets a namespace in a fresh in-memory database.
+//!
+//! Counters are stored as 8 little-endian bytes, and `increment` reads and
+//! writes them in a single transaction.
+//!
+//! [`Options::keyvalue`]: crate::Options::keyvalue
+
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::bail;
+use anyhow::Result;
+use redb::backends::InMemoryBackend;
+use redb::Database;
+use redb::ReadableTable;
+use redb::TableDefinition;
+use wasmtime::component::Accessor;
+use wasmtime::component::Linker;
+
+use crate::telemetry;
+use crate::Host;
+
+/// Values keyed by `(namespace, key)`.
+const TABLE: TableDefinition<(&str, &str), &[u8]> = TableDefinition::new("keyvalue");
+
+/// A database of namespaces.
+#[derive(Clone)]
+pub struct KeyValue {
+    db: Arc<Database>,
+}
+
+impl KeyValue {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(Database::create(path)?)
+    }
+
+    /// A database which is gone once the last handle to it is dropped.
+    pub fn in_memory() -> Result<Self> {
+        Self::new(Database::builder().create_with_backend(InMemoryBackend::new())?)
+    }
+
+    fn new(db: Database) -> Result<Self> {
+        // Read transactions fail on tables that were never written to.
+        let txn = db.begin_write()?;
+        txn.open_table(TABLE)?;
+        txn.commit()?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    pub fn namespace(&self, name: &str) -> Namespace {
+        Namespace {
+            db: self.db.clone(),
+            name: name.into(),
+        }
+    }
+}
+
+/// The keys of one instance. Every method blocks on the database.
+#[derive(Clone)]
+pub struct Namespace {
+    db: Arc<Database>,
+    name: Arc<str>,
+}
+
+impl Namespace {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(TABLE)?;
+        let value = table.get((&*self.name, key))?;
+        Ok(value.map(|value| value.value().to_vec()))
+    }
+
+    pub fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.set_many(&[(key.to_owned(), value.to_vec())])
+    }
+
+    /// Returns whether `key` was present.
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        let txn = self.db.begin_write()?;
+        let existed = txn.open_table(TABLE)?.remove((&*self.name, key))?.is_some();
+        txn.commit()?;
+        Ok(existed)
+    }
+
+    /// Reads `keys` from a single snapshot of the database.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(TABLE)?;
+        keys.iter()
+            .map(|key| {
+                let value = table.get((&*self.name, key.as_str()))?;
+                Ok(value.map(|value| value.value().to_vec()))
+            })
+            .collect()
+    }
+
+    /// Writes every entry or, if any write fails, none of them.
+    pub fn set_many(&self, entries: &[(String, Vec<u8>)]) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(TABLE)?;
+            for (key, value) in entries {
+                table.insert((&*self.name, key.as_str()), value.as_slice())?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Adds `delta` to the counter at `key`, which starts at 0, and returns
+    /// the new value. Fails, leaving the counter as it was, if the sum
+    /// overflows an `i64`.
+    pub fn increment(&self, key: &str, delta: i64) -> Result<i64> {
+        let txn = self.db.begin_write()?;
+        let value = {
+            let mut table = txn.open_table(TABLE)?;
+            let current = match table.get((&*self.name, key))? {
+                None => 0,
+                Some(value) => match <[u8; 8]>::try_from(value.value()) {
+                    Ok(bytes) => i64::from_le_bytes(bytes),
+                    Err(_) => bail!("`{key}` does not hold a counter"),
+                },
+            };
+            let Some(value) = current.checked_add(delta) else {
+                bail!("`{key}` would overflow adding {delta} to {current}");
+            };
+            table.insert((&*self.name, key), value.to_le_bytes().as_slice())?;
+            value
+        };
+        txn.commit()?;
+        Ok(value)
+    }
+}
+
+/// Runs `f` on the blocking thread pool, turning its error into the
+/// `string` the guest sees.
+fn blocking<R, F>(f: F) -> impl Future<Output = Result<Result<R, String>>>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R> + Send + 'static,
+{
+    async move {
+        let result = tokio::task::spawn_blocking(f).await?;
+        Ok(result.map_err(|err| format!("{err:#}")))
+    }
+}
+
+fn namespace(accessor: &mut Accessor<Host>) -> Namespace {
+    accessor.with(|mut view| view.get().keyvalue.clone())
+}
+
+pub fn add_to_linker(linker: &mut Linker<Host>) -> Result<()> {
+    let mut keyvalue = linker.instance("pkg:component/keyvalue")?;
+    keyvalue.func_wrap_concurrent("get", |accessor: &mut Accessor<Host>, (key,): (String,)| {
+        telemetry::import(accessor, "pkg:component/keyvalue#get");
+        let namespace = namespace(accessor);
+        Box::pin(async move { Ok((blocking(move || namespace.get(&key)).await?,)) })
+    })?;
+    keyvalue.func_wrap_concurrent(
+        "set",
+        |accessor: &mut Accessor<Host>, (key, value): (String, Vec<u8>)| {
+            telemetry::import(accessor, "pkg:component/keyvalue#set");
+            let namespace = namespace(accessor);
+            Box::pin(async move { Ok((blocking(move || namespace.set(&key, &value)).await?,)) })
+        },
+    )?;
+    keyvalue.func_wrap_concurrent(
+        "delete",
+        |accessor: &mut Accessor<Host>, (key,): (String,)| {
+            telemetry::import(accessor, "pkg:component/keyvalue#delete");
+            let namespace = namespace(accessor);
+            Box::pin(async move { Ok((blocking(move || namespace.delete(&key)).await?,)) })
+        },
+    )?;
+    keyvalue.func_wrap_concurrent(
+        "increment",
+        |accessor: &mut Accessor<Host>, (key, delta): (String, i64)| {
+            telemetry::import(accessor, "pkg:component/keyvalue#increment");
+            let namespace = namespace(accessor);
+            Box::pin(async move {
+                let value = blocking(move || namespace.increment(&key, delta)).await?;
+                Ok((value,))
+            })
+        },
+    )?;
+    keyvalue.func_wrap_concurrent(
+        "get-many",
+        |accessor: &mut Accessor<Host>, (keys,): (Vec<String>,)| {
+            telemetry::import(accessor, "pkg:component/keyvalue#get-many");
+            let namespace = namespace(accessor);
+            Box::pin(async move { Ok((blocking(move || namespace.get_many(&keys)).await?,)) })
+        },
+    )?;
+    keyvalue.func_wrap_concurrent(
+        "set-many",
+        |accessor: &mut Accessor<Host>, (entries,): (Vec<(String, Vec<u8>)>,)| {
+            telemetry::import(accessor, "pkg:component/keyvalue#set-many");
+            let namespace = namespace(accessor);
+            Box::pin(async move { Ok((blocking(move || namespace.set_many(&entries)).await?,)) })
+        },
+    )?;
+    Ok(())
+}
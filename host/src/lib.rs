@@ -3,24 +3,32 @@ use anyhow::Result;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::error::Error;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use wasmtime::component::Accessor;
 use wasmtime::component::AccessorTask;
 use wasmtime::component::Component;
 use wasmtime::component::ComponentExportIndex;
+use wasmtime::component::ComponentNamedList;
+use wasmtime::component::ComponentType;
 use wasmtime::component::ErrorContext;
 use wasmtime::component::HostFuture;
 use wasmtime::component::HostStream;
 use wasmtime::component::Instance;
+use wasmtime::component::Lift;
 use wasmtime::component::Linker;
+use wasmtime::component::Lower;
 use wasmtime::component::PromisesUnordered;
 use wasmtime::component::ResourceTable;
 use wasmtime::component::StreamReader;
 use wasmtime::component::StreamWriter;
+use wasmtime::component::Type;
 use wasmtime::component::TypedFunc;
 use wasmtime::component::VecBuffer;
 use wasmtime::CacheStore;
+use wasmtime::CallHook;
 use wasmtime::Config;
 use wasmtime::Engine;
 use wasmtime::Store;
@@ -45,7 +53,44 @@ use wasmtime_wasi::WasiCtx;
 use wasmtime_wasi::WasiCtxBuilder;
 use wasmtime_wasi::WasiView;
 
-pub const GUEST: &str = concat!(env!("OUT_DIR"), "/wasm32-wasip1/debug/guest.component.wasm");
+pub mod command;
+pub mod components;
+pub mod concurrent;
+pub mod deterministic;
+pub mod dynamic;
+pub mod engine;
+pub mod fixture;
+pub mod json;
+pub mod keyvalue;
+pub mod metrics;
+pub mod pool;
+pub mod profiling;
+pub mod reload;
+pub mod scratch;
+pub mod services;
+pub mod stdio;
+pub mod streams;
+pub mod telemetry;
+
+use deterministic::Deterministic;
+use deterministic::VirtualClock;
+use fixture::Fixture;
+use keyvalue::KeyValue;
+use keyvalue::Namespace;
+use metrics::CallTimer;
+use metrics::HighWater;
+use metrics::METRICS;
+use profiling::Profiler;
+use profiling::Profiling;
+use scratch::Quota;
+use scratch::Scratch;
+use services::Services;
+use stdio::Capture;
+use stdio::Input;
+use stdio::Output;
+use telemetry::Transitions;
+use tempfile::TempDir;
+use tracing::Instrument;
 
 pub struct Host {
     ctx: WasiCtx,
@@ -55,6 +100,160 @@ pub struct Host {
     clocks: WasiClocksCtx,
     cli: WasiCliCtx,
     filesystem: WasiFilesystemCtx,
+    scratch: Option<Scratch>,
+    /// Directories [`Fixture`] preopens were written to, removed with the
+    /// store.
+    _mounts: Vec<TempDir>,
+    clock: Option<VirtualClock>,
+    stdout: Option<Capture>,
+    stderr: Option<Capture>,
+    transitions: Transitions,
+    high_water: HighWater,
+    profiler: Option<Profiler>,
+    services: Services,
+    keyvalue: Namespace,
+}
+
+/// Knobs for [`init_with`]. `Options::default()` is what [`init`] uses.
+#[derive(Default)]
+pub struct Options {
+    /// Component to load, by default the `guest` crate's.
+    pub component: ComponentSource,
+    /// Contents of the read-only `data` preopen.
+    pub data: DataDir,
+    /// Mount a fresh writable `scratch` preopen, limited by the given quota.
+    pub scratch: Option<Quota>,
+    /// Replace real time and OS entropy with a [`VirtualClock`] and a seeded
+    /// RNG.
+    pub deterministic: Option<Deterministic>,
+    /// Give each store this much fuel, and report what calls consume.
+    pub fuel: Option<u64>,
+    /// Off by default; see [`Profiling::from_env`] for `HOST_PROFILE`.
+    pub profiling: Profiling,
+    /// State and model backend behind `pkg:component/host-services`.
+    pub services: Services,
+    /// Where `pkg:component/keyvalue` keeps the guest's state. Defaults to a
+    /// namespace in a fresh in-memory database.
+    pub keyvalue: Option<Namespace>,
+    pub stdin: Input,
+    pub stdout: Output,
+    pub stderr: Output,
+}
+
+/// Where the component comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComponentSource {
+    /// One of the [`components`] embedded in the host binary.
+    Embedded(&'static components::Guest),
+    /// A component file on the host.
+    Path(PathBuf),
+}
+
+impl Default for ComponentSource {
+    fn default() -> Self {
+        ComponentSource::Embedded(&components::GUEST)
+    }
+}
+
+/// Where the `data` preopen comes from.
+pub enum DataDir {
+    /// A directory on the host.
+    Path(PathBuf),
+    /// Files spelled out in memory and written to a temporary directory, see
+    /// [`Fixture`].
+    Fixture(Fixture),
+}
+
+impl Default for DataDir {
+    fn default() -> Self {
+        DataDir::Path(concat!(env!("CARGO_MANIFEST_DIR"), "/data").into())
+    }
+}
+
+impl Host {
+    pub fn scratch(&self) -> Option<&Scratch> {
+        self.scratch.as_ref()
+    }
+
+    /// The clock to advance when running with [`Options::deterministic`].
+    pub fn clock(&self) -> Option<&VirtualClock> {
+        self.clock.as_ref()
+    }
+
+    /// Everything the guest wrote to stdout, when running with
+    /// [`Output::Capture`].
+    pub fn stdout(&self) -> Option<&Capture> {
+        self.stdout.as_ref()
+    }
+
+    /// Everything the guest wrote to stderr, when running with
+    /// [`Output::Capture`].
+    pub fn stderr(&self) -> Option<&Capture> {
+        self.stderr.as_ref()
+    }
+
+    pub fn services(&self) -> &Services {
+        &self.services
+    }
+
+    pub fn keyvalue(&self) -> &Namespace {
+        &self.keyvalue
+    }
+
+    fn call_hook(&mut self, hook: CallHook) -> Result<()> {
+        self.transitions.record(hook);
+        Ok(())
+    }
+}
+
+/// Host-side mirror of `pkg:component/intf#request`.
+#[derive(ComponentType, Lift, Lower, Debug, Clone, PartialEq, Eq)]
+#[component(record)]
+pub struct Request {
+    pub message: String,
+}
+
+/// Host-side mirror of `pkg:component/intf#response`.
+#[derive(ComponentType, Lift, Lower, Debug, Clone, PartialEq, Eq)]
+#[component(record)]
+pub struct Response {
+    pub message: String,
+}
+
+/// Host-side mirror of `pkg:component/intf#error`.
+#[derive(ComponentType, Lift, Lower, Debug, Clone, PartialEq, Eq)]
+#[component(variant)]
+pub enum FileError {
+    #[component(name = "no-such-preopen")]
+    NoSuchPreopen(String),
+    #[component(name = "filesystem")]
+    Filesystem(String),
+    #[component(name = "quota-exceeded")]
+    QuotaExceeded,
+}
+
+/// Host-side mirror of `pkg:component/intf#sample`.
+#[derive(ComponentType, Lift, Lower, Debug, Clone, PartialEq, Eq)]
+#[component(record)]
+pub struct Sample {
+    monotonic: u64,
+    #[component(name = "wall-seconds")]
+    wall_seconds: u64,
+    #[component(name = "wall-nanoseconds")]
+    wall_nanoseconds: u32,
+    random: Vec<u8>,
+}
+
+/// Host-side mirror of `pkg:component/intf#window-stats`.
+#[derive(ComponentType, Lift, Lower, Debug, Clone, PartialEq, Eq)]
+#[component(record)]
+pub struct WindowStats {
+    start: u64,
+    bids: u64,
+    #[component(name = "max-price")]
+    max_price: u64,
+    #[component(name = "total-price")]
+    total_price: u64,
 }
 
 impl WasiView for Host {
@@ -114,7 +313,10 @@ impl CacheStore for Cache {
     fn get(&self, key: &[u8]) -> Option<Cow<[u8]>> {
         let mut cache = CACHE.lock().unwrap();
         let cache = cache.get_or_insert_with(HashMap::new);
-        cache.get(key).map(|s| s.to_vec().into())
+        let value = cache.get(key);
+        let result = if value.is_some() { "hit" } else { "miss" };
+        METRICS.cache_requests.with_label_values(&[result]).inc();
+        value.map(|s| s.to_vec().into())
     }
 
     fn insert(&self, key: &[u8], value: Vec<u8>) -> bool {
@@ -126,25 +328,117 @@ impl CacheStore for Cache {
 }
 
 pub async fn init() -> (Instance, Store<Host>, ComponentExportIndex) {
+    init_with(Options::default()).await
+}
+
+pub async fn init_with(options: Options) -> (Instance, Store<Host>, ComponentExportIndex) {
+    let (instance, mut store) = instantiate(options).await;
+    let intf_export = instance
+        .get_export(&mut store, None, "pkg:component/intf")
+        .unwrap();
+
+    (instance, store, intf_export)
+}
+
+/// Like [`init_with`], for components that do not export
+/// `pkg:component/intf`, such as the other entries in [`components`].
+pub async fn instantiate(options: Options) -> (Instance, Store<Host>) {
+    try_instantiate(options).await.unwrap()
+}
+
+/// Like [`instantiate`], but returns an error for a component which fails to
+/// compile or link instead of panicking.
+pub async fn try_instantiate(options: Options) -> Result<(Instance, Store<Host>)> {
+    let component = compile(&options)?;
+    try_instantiate_compiled(&component, options).await
+}
+
+/// Compiles the component `options` asks for, on an engine configured for its
+/// fuel and profiling settings. This blocks, so async callers that compile
+/// more than once, or on a busy runtime, should move it to a blocking thread.
+pub fn compile(options: &Options) -> Result<Component> {
     let mut config = Config::new();
-    config.async_support(true);
-    config.wasm_component_model_async(true);
-    config.enable_incremental_compilation(Arc::new(Cache)).unwrap();
-    config.cache_config_load("config.toml").unwrap();
+    engine::configure(&mut config);
+    config.consume_fuel(options.fuel.is_some());
+    options.profiling.configure(&mut config);
+    config.enable_incremental_compilation(Arc::new(Cache))?;
+    // The host binary may run away from its source tree.
+    let cache_config = concat!(env!("CARGO_MANIFEST_DIR"), "/config.toml");
+    if std::path::Path::new(cache_config).exists() {
+        config.cache_config_load(cache_config)?;
+    }
+    let engine = Engine::new(&config)?;
+    match &options.component {
+        ComponentSource::Embedded(guest) => guest.load(&engine),
+        ComponentSource::Path(path) => Component::from_file(&engine, path),
+    }
+}
+
+/// Instantiates a component from [`compile`], which may be shared by any
+/// number of stores. `options` must ask for the same fuel and profiling
+/// settings as the options it was compiled with; its `component` is ignored.
+pub async fn try_instantiate_compiled(
+    component: &Component,
+    options: Options,
+) -> Result<(Instance, Store<Host>)> {
+    let mut ctx = WasiCtxBuilder::new();
+    let mut cli = WasiCliCtx::default();
+    stdio::configure_input(options.stdin, &mut ctx, &mut cli);
+    let stdout = stdio::configure_output(stdio::Stream::Stdout, options.stdout, &mut ctx, &mut cli);
+    let stderr = stdio::configure_output(stdio::Stream::Stderr, options.stderr, &mut ctx, &mut cli);
+    let mut random = WasiRandomCtx::default();
+    let mut clocks = WasiClocksCtx::default();
+    let mut clock = None;
+    if let Some(deterministic) = &options.deterministic {
+        let virtual_clock = deterministic.clock();
+        deterministic.configure(&virtual_clock, &mut ctx);
+        random = deterministic.random();
+        clocks = deterministic.clocks(&virtual_clock);
+        clock = Some(virtual_clock);
+    }
 
     let mut host = Host {
         table: ResourceTable::new(),
         sockets: WasiSocketsCtx::default(),
-        random: WasiRandomCtx::default(),
-        clocks: WasiClocksCtx::default(),
-        cli: WasiCliCtx::default(),
+        random,
+        clocks,
+        cli,
         filesystem: WasiFilesystemCtx::default(),
-        ctx: WasiCtxBuilder::new().inherit_stdio().build(),
+        ctx: ctx.build(),
+        scratch: options.scratch.map(Scratch::new).transpose()?,
+        _mounts: Vec::new(),
+        clock,
+        stdout,
+        stderr,
+        transitions: Transitions::default(),
+        high_water: HighWater,
+        profiler: None,
+        services: options.services,
+        keyvalue: match options.keyvalue {
+            Some(namespace) => namespace,
+            None => KeyValue::in_memory()?.namespace("default"),
+        },
     };
 
+    let data = match options.data {
+        DataDir::Path(path) => path,
+        DataDir::Fixture(fixture) => {
+            let dir = fixture.write_to_temp_dir()?;
+            let path = dir.path().to_path_buf();
+            host._mounts.push(dir);
+            path
+        }
+    };
     host.filesystem
-        .preopened_dir("data", "data", DirPerms::READ, FilePerms::READ)
-        .unwrap();
+        .preopened_dir(data, "data", DirPerms::READ, FilePerms::READ)?;
+    if let Some(scratch) = &host.scratch {
+        host.filesystem.preopened_dir(
+            scratch.path(),
+            "scratch",
+            DirPerms::all(),
+            FilePerms::all(),
+        )?;
+    }
     host.sockets.socket_addr_check = SocketAddrCheck::new(|_, _| Box::pin(async { true }));
     host.sockets.allowed_network_uses = AllowedNetworkUses {
         ip_name_lookup: true,
@@ -152,27 +446,78 @@ pub async fn init() -> (Instance, Store<Host>, ComponentExportIndex) {
         tcp: true,
     };
 
-    let engine = Engine::new(&config).unwrap();
-    let component = Component::from_file(&engine, &GUEST).unwrap();
-    let mut store = Store::new(&engine, host);
-    let mut linker = Linker::new(&engine);
-    wasmtime_wasi::add_to_linker_async(&mut linker).unwrap();
-    wasmtime_wasi::p3::sockets::add_to_linker(&mut linker).unwrap();
-    wasmtime_wasi::p3::random::add_to_linker(&mut linker).unwrap();
-    wasmtime_wasi::p3::clocks::add_to_linker(&mut linker).unwrap();
-    wasmtime_wasi::p3::cli::add_to_linker(&mut linker).unwrap();
-    wasmtime_wasi::p3::filesystem::add_to_linker::<Host>(&mut linker).unwrap();
+    let instantiation = METRICS.instantiation.start_timer();
+    let engine = component.engine();
+    let mut store = Store::new(engine, host);
+    store.call_hook(|mut store, hook| {
+        profiling::call_hook(&mut store, hook);
+        store.data_mut().call_hook(hook)
+    });
+    store.limiter(|host| &mut host.high_water);
+    if let Some(fuel) = options.fuel {
+        store.set_fuel(fuel)?;
+    }
+    options.profiling.install(&mut store, component)?;
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::add_to_linker_async(&mut linker)?;
+    wasmtime_wasi::p3::sockets::add_to_linker(&mut linker)?;
+    wasmtime_wasi::p3::random::add_to_linker(&mut linker)?;
+    wasmtime_wasi::p3::clocks::add_to_linker(&mut linker)?;
+    wasmtime_wasi::p3::cli::add_to_linker(&mut linker)?;
+    wasmtime_wasi::p3::filesystem::add_to_linker::<Host>(&mut linker)?;
+    if store.data().scratch.is_some() {
+        scratch::add_to_linker(&mut linker)?;
+    }
+    services::add_to_linker(&mut linker)?;
+    keyvalue::add_to_linker(&mut linker)?;
+    if options.deterministic.is_some() {
+        deterministic::add_timers_to_linker(&mut linker)?;
+    }
+
+    let instance = linker.instantiate_async(&mut store, component).await?;
+    instantiation.observe_duration();
 
-    let instance = linker
-        .instantiate_async(&mut store, &component)
-        .await
-        .unwrap();
+    Ok((instance, store))
+}
 
-    let intf_export = instance
-        .get_export(&mut store, None, "pkg:component/intf")
-        .unwrap();
+/// Calls `func` and then its `post_return_async`, inside a span named after
+/// `export`, and records the call in [`METRICS`].
+pub async fn call<Params, Results>(
+    store: &mut Store<Host>,
+    export: &str,
+    func: &TypedFunc<Params, Results>,
+    params: Params,
+) -> Result<Results>
+where
+    Params: ComponentNamedList + Lower + Send + Sync,
+    Results: ComponentNamedList + Lift + Send + Sync + 'static,
+{
+    async {
+        let timer = CallTimer::start(store, export);
+        let results = async {
+            let results = func
+                .call_async(&mut *store, params)
+                .instrument(tracing::debug_span!("call_async"))
+                .await?;
+            func.post_return_async(&mut *store)
+                .instrument(tracing::debug_span!("post_return_async"))
+                .await?;
+            Ok(results)
+        }
+        .await;
+        timer.finish(store);
+        results
+    }
+    .instrument(tracing::info_span!("call", export))
+    .await
+}
 
-    (instance, store, intf_export)
+// Tests have no `main` to install a subscriber from, so the test binary
+// installs it before any test runs.
+#[cfg(test)]
+#[ctor::ctor]
+fn install_telemetry() {
+    telemetry::install();
 }
 
 // test1: async fn(String) -> String
@@ -185,14 +530,11 @@ async fn test1() {
     let func: TypedFunc<(String,), (String,)> =
         instance.get_typed_func(&mut store, export).unwrap();
 
-    let (result,) = func
-        .call_async(&mut store, ("Hello".to_owned(),))
+    let (result,) = call(&mut store, "test", &func, ("Hello".to_owned(),))
         .await
         .unwrap();
 
-    func.post_return_async(&mut store).await.unwrap();
-
-    println!("Result: {:?}", result);
+    tracing::info!(?result);
 }
 
 // test2: async fn<String> -> Future<String>
@@ -206,15 +548,12 @@ async fn test2() {
     let func2: TypedFunc<(String,), (HostFuture<String>,)> =
         instance.get_typed_func(&mut store, export).unwrap();
 
-    let (result,) = func2
-        .call_async(&mut store, ("Hello".to_owned(),))
+    let (result,) = call(&mut store, "test2", &func2, ("Hello".to_owned(),))
         .await
         .unwrap();
 
-    func2.post_return_async(&mut store).await.unwrap();
-
-    if let Ok(Some(result)) = result.into_reader(&mut store).read().get(&mut store).await {
-        println!("Result: {:?}", result);
+    if let Ok(Some(result)) = streams::read_future(&mut store, result).await {
+        tracing::info!(?result);
     }
 }
 
@@ -231,17 +570,13 @@ async fn test3() {
 
     let (tx, rx) = instance.future(&mut store).unwrap();
 
-    let handle = tokio::task::spawn(async move {
-        tx.write("Hello World! (test3)".to_owned())
-            .into_future()
-            .await;
-    });
-
-    let (result,) = func3.call_async(&mut store, (rx.into(),)).await.unwrap();
+    let handle = streams::spawn_future_writer(tx, "Hello World! (test3)".to_owned());
 
-    func3.post_return_async(&mut store).await.unwrap();
+    let (result,) = call(&mut store, "test3", &func3, (rx.into(),))
+        .await
+        .unwrap();
 
-    println!("Result: {:?}", result);
+    tracing::info!(?result);
 
     handle.await.unwrap();
 }
@@ -282,36 +617,2033 @@ async fn test4() {
     while let Ok(Some(event)) = set.next(&mut store).await {
         match event {
             Event::Write((Some(tx), _)) => {
-                println!("Writing");
+                tracing::debug!("Writing");
                 set.push(
                     tx.write(VecBuffer::from(vec!["Hello World! (test4)".to_owned()]))
                         .map(Event::Write),
                 );
             }
             Event::Write(_) => {
-                println!("Write finished");
+                tracing::debug!("Write finished");
             }
             Event::Read((Some(reader), buf)) => {
-                println!("Reading: {:?}", buf);
+                tracing::info!(?buf, "Reading");
                 set.push(reader.read(buf).map(Event::Read));
             }
             Event::Read(_) => {
-                println!("Read error");
+                tracing::warn!("Read error");
             }
         }
     }
-    println!("All done");
+    tracing::info!("All done");
 }
 
 // get-files: async fn() -> String
 #[tokio::test]
 async fn test_get_files_p3() {
     let (instance, mut store, intf_export) = init().await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "get-files-p3")
+        .unwrap();
+    let func: TypedFunc<(), (String,)> = instance.get_typed_func(&mut store, export).unwrap();
+    let (result,) = call(&mut store, "get-files-p3", &func, ()).await.unwrap();
+    tracing::info!(%result);
+}
+
+// write-file: async fn(String, Stream<u8>) -> Future<Result<(), Error>>
+// read-file: async fn(String) -> (Stream<u8>, Future<Result<(), Error>>)
+#[tokio::test]
+async fn test_write_read_file() {
+    use sha2::Digest;
+    use sha2::Sha256;
+
+    let (instance, mut store, intf_export) = init_with(Options {
+        scratch: Some(Quota::default()),
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "write-file")
+        .unwrap();
+    let write_file: TypedFunc<(String, HostStream<u8>), (HostFuture<Result<(), FileError>>,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    let data: Vec<u8> = (0..8 * 1024 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect();
+    let expected = Sha256::digest(&data);
+
+    let (tx, rx) = instance
+        .stream::<u8, VecBuffer<u8>, Vec<u8>, _, _>(&mut store)
+        .unwrap();
+    let writer = streams::spawn_writer(tx, data, 64 * 1024);
+
+    let (result,) = write_file
+        .call_async(&mut store, ("scratch/blob.bin".to_owned(), rx.into()))
+        .await
+        .unwrap();
+    write_file.post_return_async(&mut store).await.unwrap();
+
+    let result = result.into_reader(&mut store).read().get(&mut store).await;
+    assert_eq!(result.unwrap(), Some(Ok(())));
+    assert_eq!(writer.await.unwrap(), 8 * 1024 * 1024);
+
+    let scratch = store.data().scratch().unwrap();
+    let written = std::fs::read(scratch.path().join("blob.bin")).unwrap();
+    assert_eq!(Sha256::digest(&written), expected);
+
+    let (read, result) = read_file(&instance, &mut store, &intf_export, "scratch/blob.bin").await;
+    assert_eq!(result, Some(Ok(())));
+    assert_eq!(read.len(), 8 * 1024 * 1024);
+    assert_eq!(Sha256::digest(&read), expected);
+
+    let (read, result) = read_file(&instance, &mut store, &intf_export, "scratch/missing").await;
+    assert!(read.is_empty());
+    assert!(
+        matches!(&result, Some(Err(FileError::Filesystem(_)))),
+        "{result:?}"
+    );
+    let (_, result) = read_file(&instance, &mut store, &intf_export, "nowhere/blob.bin").await;
+    assert_eq!(
+        result,
+        Some(Err(FileError::NoSuchPreopen("nowhere".to_owned())))
+    );
+}
+
+/// Calls `read-file`, returning what the stream carried and what the future
+/// resolved to.
+#[cfg(test)]
+async fn read_file(
+    instance: &Instance,
+    store: &mut Store<Host>,
+    intf_export: &ComponentExportIndex,
+    path: &str,
+) -> (Vec<u8>, Option<Result<(), FileError>>) {
+    let export = instance
+        .get_export(&mut *store, Some(intf_export), "read-file")
+        .unwrap();
+    let func: TypedFunc<(String,), ((HostStream<u8>, HostFuture<Result<(), FileError>>),)> =
+        instance.get_typed_func(&mut *store, export).unwrap();
+    let ((data, result),) = call(store, "read-file", &func, (path.to_owned(),))
+        .await
+        .unwrap();
+    let data = streams::read_all(store, data).await.unwrap();
+    let result = streams::read_future(store, result).await.unwrap();
+    (data, result)
+}
+
+// write-file: async fn(String, Stream<u8>) -> Future<Result<(), Error>>
+#[tokio::test]
+async fn test_write_file_without_preopen() {
+    let (instance, mut store, intf_export) = init().await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "write-file")
+        .unwrap();
+    let write_file: TypedFunc<(String, HostStream<u8>), (HostFuture<Result<(), FileError>>,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+
+    let (tx, rx) = instance
+        .stream::<u8, VecBuffer<u8>, Vec<u8>, _, _>(&mut store)
+        .unwrap();
+    drop(tx);
+
+    let (result,) = write_file
+        .call_async(&mut store, ("scratch/blob.bin".to_owned(), rx.into()))
+        .await
+        .unwrap();
+    write_file.post_return_async(&mut store).await.unwrap();
+
+    let result = result.into_reader(&mut store).read().get(&mut store).await;
+    assert_eq!(
+        result.unwrap(),
+        Some(Err(FileError::NoSuchPreopen("scratch".to_owned())))
+    );
+}
+
+#[cfg(test)]
+async fn write_scratch_file(
+    instance: &Instance,
+    store: &mut Store<Host>,
+    intf_export: &ComponentExportIndex,
+    path: &str,
+    data: Vec<u8>,
+) -> Result<Option<Result<(), FileError>>> {
+    let export = instance
+        .get_export(&mut *store, Some(intf_export), "write-file")
+        .unwrap();
+    let write_file: TypedFunc<(String, HostStream<u8>), (HostFuture<Result<(), FileError>>,)> =
+        instance.get_typed_func(&mut *store, export)?;
+
+    let (tx, rx) = instance.stream::<u8, VecBuffer<u8>, Vec<u8>, _, _>(&mut *store)?;
+    let writer = streams::spawn_writer(tx, data, 64 * 1024);
+
+    let (result,) = write_file
+        .call_async(&mut *store, (path.to_owned(), rx.into()))
+        .await?;
+    write_file.post_return_async(&mut *store).await?;
+
+    let result = result
+        .into_reader(&mut *store)
+        .read()
+        .get(&mut *store)
+        .await?;
+    writer.await?;
+    Ok(result)
+}
+
+// The write that would take the directory past its byte quota fails, leaves
+// nothing past the quota on disk, and the instance keeps working.
+#[tokio::test]
+async fn test_scratch_byte_quota() {
+    let (instance, mut store, intf_export) = init_with(Options {
+        scratch: Some(Quota {
+            max_bytes: 1024 * 1024,
+            max_files: 16,
+        }),
+        ..Options::default()
+    })
+    .await;
+
+    let data = vec![0xAB; 512 * 1024];
+    let written = write_scratch_file(&instance, &mut store, &intf_export, "scratch/a.bin", data)
+        .await
+        .unwrap();
+    assert_eq!(written, Some(Ok(())));
+
+    let data = vec![0xCD; 2 * 1024 * 1024];
+    let written = write_scratch_file(&instance, &mut store, &intf_export, "scratch/b.bin", data)
+        .await
+        .unwrap();
+    assert_eq!(written, Some(Err(FileError::QuotaExceeded)));
+    let usage = store.data().scratch().unwrap().usage().unwrap();
+    assert!(usage.bytes <= 1024 * 1024, "{usage:?}");
+
+    // Truncating a file credits its bytes back.
+    let data = vec![0xEF; 512 * 1024];
+    let written = write_scratch_file(&instance, &mut store, &intf_export, "scratch/a.bin", data)
+        .await
+        .unwrap();
+    assert_eq!(written, Some(Ok(())));
+}
+
+// Creating a file past the file quota fails before anything is created.
+#[tokio::test]
+async fn test_scratch_file_quota() {
+    let (instance, mut store, intf_export) = init_with(Options {
+        scratch: Some(Quota {
+            max_bytes: 1024 * 1024,
+            max_files: 2,
+        }),
+        ..Options::default()
+    })
+    .await;
+
+    let mut results = Vec::new();
+    for i in 0..3 {
+        let path = format!("scratch/{i}.txt");
+        let data = b"hello".to_vec();
+        let written = write_scratch_file(&instance, &mut store, &intf_export, &path, data)
+            .await
+            .unwrap();
+        results.push(written);
+    }
+    assert_eq!(
+        results,
+        [
+            Some(Ok(())),
+            Some(Ok(())),
+            Some(Err(FileError::QuotaExceeded))
+        ]
+    );
+    let scratch = store.data().scratch().unwrap();
+    assert!(!scratch.path().join("2.txt").exists());
+    assert_eq!(scratch.usage().unwrap().files, 2);
+
+    // Files that already exist can still be rewritten.
+    let data = b"again".to_vec();
+    let written = write_scratch_file(&instance, &mut store, &intf_export, "scratch/0.txt", data)
+        .await
+        .unwrap();
+    assert_eq!(written, Some(Ok(())));
+}
+
+// The scratch directory is removed together with the store.
+#[tokio::test]
+async fn test_scratch_cleanup() {
+    let (instance, mut store, intf_export) = init_with(Options {
+        scratch: Some(Quota::default()),
+        ..Options::default()
+    })
+    .await;
+
+    let data = b"spilled state".to_vec();
+    let result = write_scratch_file(&instance, &mut store, &intf_export, "scratch/s.bin", data)
+        .await
+        .unwrap();
+    assert_eq!(result, Some(Ok(())));
+
+    let path = store.data().scratch().unwrap().path().to_path_buf();
+    assert!(path.join("s.bin").exists());
+    drop(store);
+    assert!(!path.exists());
+}
+
+#[cfg(test)]
+const BIDS_FIXTURE: &str = "\
+1000,1001,73134520,channel-7568,https://www.nexmark.com/item.htm?query=1,1700000000,a
+1000,1001,499920,Apple,https://www.nexmark.com/item.htm?query=1,1700000001,b
+";
+
+// get-files-p3 / read-file against a fixture `data` preopen
+#[tokio::test]
+async fn test_fixture_data() {
+    let mut fixture = Fixture::new();
+    fixture.insert("bids.csv", BIDS_FIXTURE).unwrap();
+    fixture.insert("nested/empty.csv", "").unwrap();
+    let (instance, mut store, intf_export) = init_with(Options {
+        data: DataDir::Fixture(fixture),
+        ..Options::default()
+    })
+    .await;
+
     let export = instance
         .get_export(&mut store, Some(&intf_export), "get-files-p3")
         .unwrap();
     let func: TypedFunc<(), (String,)> = instance.get_typed_func(&mut store, export).unwrap();
     let (result,) = func.call_async(&mut store, ()).await.unwrap();
     func.post_return_async(&mut store).await.unwrap();
-    println!("Result: {}", result);
+
+    let mut files: Vec<&str> = result.lines().collect();
+    files.sort();
+    assert_eq!(files, ["bids.csv", "nested"]);
+
+    let (contents, result) = read_file(&instance, &mut store, &intf_export, "data/bids.csv").await;
+    assert_eq!(result, Some(Ok(())));
+    assert_eq!(contents, BIDS_FIXTURE.as_bytes());
+}
+
+// read-file against a `data` preopen populated from a tar archive
+#[tokio::test]
+async fn test_fixture_data_from_tar() {
+    let mut archive = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(BIDS_FIXTURE.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive
+        .append_data(&mut header, "fixtures/bids.csv", BIDS_FIXTURE.as_bytes())
+        .unwrap();
+    let archive = archive.into_inner().unwrap();
+
+    let fixture = Fixture::from_tar(archive.as_slice()).unwrap();
+    assert_eq!(
+        fixture.get("fixtures/bids.csv"),
+        Some(BIDS_FIXTURE.as_bytes())
+    );
+    let (instance, mut store, intf_export) = init_with(Options {
+        data: DataDir::Fixture(fixture),
+        ..Options::default()
+    })
+    .await;
+
+    let (contents, result) = read_file(
+        &instance,
+        &mut store,
+        &intf_export,
+        "data/fixtures/bids.csv",
+    )
+    .await;
+    assert_eq!(result, Some(Ok(())));
+    assert_eq!(contents, BIDS_FIXTURE.as_bytes());
+}
+
+#[test]
+fn test_fixture_paths() {
+    let mut fixture = Fixture::new();
+    assert!(fixture.insert("../bids.csv", "").is_err());
+    assert!(fixture.insert("/etc/passwd", "").is_err());
+    assert!(fixture.insert("", "").is_err());
+    assert!(fixture.insert(".", "").is_err());
+    assert!(fixture.insert("ok/../../bids.csv", "").is_err());
+    assert!(fixture.insert("./ok/bids.csv", "").is_ok());
+    assert_eq!(fixture.get("ok/bids.csv"), Some(&b""[..]));
+    // A path can be a file or a directory, not both.
+    assert!(fixture.insert("ok/bids.csv/more.csv", "").is_err());
+    assert!(fixture.insert("ok", "").is_err());
+    assert!(fixture.insert("ok/bids.csv", "replaced").is_ok());
+    fixture.write_to_temp_dir().unwrap();
+}
+
+#[cfg(test)]
+async fn sample(seed: u64, advance: Option<Duration>) -> (Sample, Sample) {
+    let (instance, mut store, intf_export) = init_with(Options {
+        deterministic: Some(Deterministic::new(seed)),
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "sample")
+        .unwrap();
+    let func: TypedFunc<(u32,), (Sample,)> = instance.get_typed_func(&mut store, export).unwrap();
+
+    let (first,) = func.call_async(&mut store, (32,)).await.unwrap();
+    func.post_return_async(&mut store).await.unwrap();
+    if let Some(by) = advance {
+        store.data().clock().unwrap().advance(by);
+    }
+    let (second,) = func.call_async(&mut store, (32,)).await.unwrap();
+    func.post_return_async(&mut store).await.unwrap();
+    (first, second)
+}
+
+// sample: async fn(u32) -> Sample
+#[tokio::test]
+async fn test_deterministic_replay() {
+    let seed = Deterministic::from_env().unwrap().seed;
+    let run = sample(seed, None).await;
+    let replay = sample(seed, None).await;
+    assert_eq!(run, replay);
+    assert_ne!(run.0.random, run.1.random);
+
+    let other = sample(seed.wrapping_add(1), None).await;
+    assert_ne!(run.0.random, other.0.random);
+}
+
+// sample: async fn(u32) -> Sample
+#[tokio::test]
+async fn test_deterministic_clock() {
+    let (first, second) = sample(7, Some(Duration::from_millis(1500))).await;
+    assert_eq!(first.monotonic, 0);
+    assert_eq!(first.wall_seconds, 1_700_000_000);
+    assert_eq!(first.wall_nanoseconds, 0);
+    assert_eq!(second.monotonic, 1_500_000_000);
+    assert_eq!(second.wall_seconds, 1_700_000_001);
+    assert_eq!(second.wall_nanoseconds, 500_000_000);
+}
+
+// Timers registered on a virtual clock fire in deadline order.
+#[tokio::test]
+async fn test_virtual_timers() {
+    type Fired = Arc<Mutex<Vec<&'static str>>>;
+    fn record(
+        fired: &Fired,
+        name: &'static str,
+        timer: impl std::future::Future<Output = ()> + Send + 'static,
+    ) -> tokio::task::JoinHandle<()> {
+        let fired = fired.clone();
+        tokio::spawn(async move {
+            timer.await;
+            fired.lock().unwrap().push(name);
+        })
+    }
+
+    let clock = Deterministic::new(0).clock();
+    let fired = Fired::default();
+    let late = record(&fired, "late", clock.sleep(Duration::from_millis(30)));
+    let early = record(&fired, "early", clock.sleep(Duration::from_millis(10)));
+    let middle = record(&fired, "middle", clock.sleep_until(20_000_000));
+    assert_eq!(clock.pending_timers(), 3);
+
+    clock.advance(Duration::from_millis(25));
+    early.await.unwrap();
+    middle.await.unwrap();
+    assert_eq!(clock.pending_timers(), 1);
+    assert!(!late.is_finished());
+    assert_eq!(*fired.lock().unwrap(), ["early", "middle"]);
+
+    clock.advance(Duration::from_millis(5));
+    late.await.unwrap();
+    assert_eq!(clock.monotonic(), 30_000_000);
+    assert_eq!(*fired.lock().unwrap(), ["early", "middle", "late"]);
+}
+
+// throttle: async fn(Stream<String>, u64) -> Stream<String>
+#[tokio::test]
+async fn test_throttle() {
+    let (instance, mut store, intf_export) = init_with(Options {
+        deterministic: Some(Deterministic::new(0)),
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "throttle")
+        .unwrap();
+    let func: TypedFunc<(HostStream<String>, u64), (HostStream<String>,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+
+    let interval = Duration::from_secs(1);
+    let items: Vec<String> = (0..3).map(|i| format!("item-{i}")).collect();
+    let (tx, rx) = instance
+        .stream::<String, VecBuffer<String>, Vec<String>, _, _>(&mut store)
+        .unwrap();
+    let writer = streams::spawn_writer(tx, items.clone(), 3);
+
+    let (result,) = func
+        .call_async(&mut store, (rx.into(), interval.as_nanos() as u64))
+        .await
+        .unwrap();
+    func.post_return_async(&mut store).await.unwrap();
+
+    // Advance virtual time whenever the guest is waiting on a timer.
+    let clock = store.data().clock().unwrap().clone();
+    let driver = tokio::spawn({
+        let clock = clock.clone();
+        async move {
+            for _ in 0..3 {
+                clock.timers_pending(1).await;
+                clock.advance(interval);
+            }
+        }
+    });
+
+    let mut received = Vec::new();
+    let mut reader = result.into_reader::<Vec<String>>(&mut store);
+    loop {
+        let (next, buf) = reader
+            .read(Vec::with_capacity(8))
+            .get(&mut store)
+            .await
+            .unwrap();
+        for item in buf {
+            received.push((item, clock.monotonic()));
+        }
+        match next {
+            Some(next) => reader = next,
+            None => break,
+        }
+    }
+    driver.await.unwrap();
+    writer.await.unwrap();
+
+    let second = interval.as_nanos() as u64;
+    assert_eq!(
+        received,
+        [
+            ("item-0".to_owned(), second),
+            ("item-1".to_owned(), 2 * second),
+            ("item-2".to_owned(), 3 * second),
+        ]
+    );
+}
+
+// print: async fn(String, String)
+#[tokio::test]
+async fn test_captured_stdio() {
+    let (instance, mut store, intf_export) = init_with(Options {
+        stdout: Output::Capture,
+        stderr: Output::Capture,
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "print")
+        .unwrap();
+    let func: TypedFunc<(String, String), ()> =
+        instance.get_typed_func(&mut store, export).unwrap();
+
+    func.call_async(&mut store, ("Hello".to_owned(), "World".to_owned()))
+        .await
+        .unwrap();
+    func.post_return_async(&mut store).await.unwrap();
+    func.call_async(&mut store, ("Again".to_owned(), String::new()))
+        .await
+        .unwrap();
+    func.post_return_async(&mut store).await.unwrap();
+
+    let host = store.data();
+    assert_eq!(host.stdout().unwrap().to_string_lossy(), "Hello\nAgain\n");
+    assert_eq!(host.stderr().unwrap().to_string_lossy(), "World\n");
+}
+
+/// Collects the `(stream, line)` of every event [`Output::Tracing`] emits.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct GuestLines(Arc<Mutex<Vec<(String, String)>>>);
+
+#[cfg(test)]
+impl GuestLines {
+    fn take(&self) -> Vec<(String, String)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for GuestLines {
+    fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+        use tracing::field::Field;
+
+        #[derive(Default)]
+        struct Fields {
+            stream: String,
+            message: String,
+        }
+
+        impl tracing::field::Visit for Fields {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                if field.name() == "stream" {
+                    self.stream = value.to_owned();
+                }
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.message = format!("{value:?}");
+                }
+            }
+        }
+
+        if event.metadata().target() == "guest" {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            self.0.lock().unwrap().push((fields.stream, fields.message));
+        }
+    }
+}
+
+// Output::Tracing joins the writes of all of a stream's writers into lines,
+// and emits what is left of the last one once the stream is dropped.
+#[tokio::test]
+async fn test_tracing_output() {
+    use tokio::io::AsyncWriteExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let lines = GuestLines::default();
+    let _subscriber =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(lines.clone()));
+
+    let mut ctx = WasiCtxBuilder::new();
+    let mut cli = WasiCliCtx::default();
+    let capture =
+        stdio::configure_output(stdio::Stream::Stderr, Output::Tracing, &mut ctx, &mut cli);
+    assert!(capture.is_none());
+
+    let mut first = cli.stderr.writer();
+    let mut second = cli.stderr.writer();
+    first.write_all(b"Hel").await.unwrap();
+    second.write_all(b"lo\nWor").await.unwrap();
+    first.write_all(b"ld\nbye").await.unwrap();
+    first.shutdown().await.unwrap();
+    let line = |line: &str| ("stderr".to_owned(), line.to_owned());
+    assert_eq!(lines.take(), [line("Hello"), line("World")]);
+
+    // The preview 2 stream lets go of its writer in a background task.
+    drop((first, second, cli, ctx));
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    let mut rest = Vec::new();
+    while rest.is_empty() {
+        assert!(std::time::Instant::now() < deadline, "partial line lost");
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        rest = lines.take();
+    }
+    assert_eq!(rest, [line("bye")]);
+}
+
+// Input::Reader is read through whichever of the stream's readers asks, so
+// they make progress through one input together.
+#[tokio::test]
+async fn test_reader_input() {
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    let (mut tx, rx) = tokio::io::duplex(64);
+    let mut ctx = WasiCtxBuilder::new();
+    let mut cli = WasiCliCtx::default();
+    stdio::configure_input(Input::Reader(Box::new(rx)), &mut ctx, &mut cli);
+
+    let mut first = cli.stdin.reader();
+    let mut second = cli.stdin.reader();
+    let mut buf = [0; 16];
+    tx.write_all(b"one\n").await.unwrap();
+    let n = first.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"one\n");
+    tx.write_all(b"two\n").await.unwrap();
+    let n = second.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"two\n");
+
+    drop(tx);
+    assert_eq!(first.read(&mut buf).await.unwrap(), 0);
+    assert_eq!(second.read(&mut buf).await.unwrap(), 0);
+}
+
+// wasi:cli/run: async fn() -> Result<(), ()>
+#[tokio::test]
+async fn test_run_filter() {
+    let (instance, mut store, _) = init_with(Options {
+        stdin: Input::bytes(BIDS_FIXTURE),
+        stdout: Output::Capture,
+        ..Options::default()
+    })
+    .await;
+
+    let result = command::run(&instance, &mut store).await.unwrap();
+    assert_eq!(result, Ok(()));
+
+    let stdout = store.data().stdout().unwrap().to_string_lossy();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            r#"{"auction":1000,"bidder":1001,"price":73134520,"channel":"channel-7568","url":"https://www.nexmark.com/item.htm?query=1","date_time":1700000000,"extra":"a"}"#,
+            r#"{"auction":1000,"bidder":1001,"price":499920,"channel":"Apple","url":"https://www.nexmark.com/item.htm?query=1","date_time":1700000001,"extra":"b"}"#,
+        ]
+    );
+}
+
+// wasi:cli/run: async fn() -> Result<(), ()>
+#[tokio::test]
+async fn test_run_filter_malformed() {
+    let (instance, mut store, _) = init_with(Options {
+        stdin: Input::bytes(format!("not,a,bid\n{BIDS_FIXTURE}")),
+        stdout: Output::Capture,
+        stderr: Output::Capture,
+        ..Options::default()
+    })
+    .await;
+
+    let result = command::run(&instance, &mut store).await.unwrap();
+    assert_eq!(result, Err(()));
+
+    let host = store.data();
+    assert_eq!(host.stdout().unwrap().to_string_lossy().lines().count(), 2);
+    assert!(host
+        .stderr()
+        .unwrap()
+        .to_string_lossy()
+        .contains("expected 7 fields, found 3"));
+}
+
+// test, test2, test3 and test4 through the dynamic layer
+#[tokio::test]
+async fn test_dynamic() {
+    use dynamic::Arg;
+    use dynamic::Dynamic;
+    use dynamic::Ret;
+    use wasmtime::component::Val;
+
+    let (instance, mut store, _) = init().await;
+    let dynamic = Dynamic::new(instance);
+
+    let signature = dynamic
+        .signature(&mut store, "pkg:component/intf#test4")
+        .unwrap();
+    assert_eq!(signature.params.len(), 1);
+    assert_eq!(signature.params[0].0, "test");
+    assert!(matches!(signature.params[0].1, Type::Stream(_)));
+
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#test",
+            vec![Arg::Value(Val::String("Hello".to_owned()))],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        results,
+        [Ret::Value(Val::String("Hello World! (test1)".to_owned()))]
+    );
+
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#test2",
+            vec![Arg::Value(Val::String("Hello".to_owned()))],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        results,
+        [Ret::Future(Some(Val::String(
+            "Hello World! (test2)".to_owned()
+        )))]
+    );
+
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#test3",
+            vec![Arg::Future(Val::String("Hello World! (test3)".to_owned()))],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        results,
+        [Ret::Value(Val::String("Hello World! (test3)".to_owned()))]
+    );
+
+    let items = (0..3).map(|i| Val::String(format!("item-{i}"))).collect();
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#test4",
+            vec![Arg::Stream(items)],
+        )
+        .await
+        .unwrap();
+    let response = Val::String("Response".to_owned());
+    match &results[..] {
+        [Ret::Stream(items)] => {
+            assert!(!items.is_empty());
+            assert!(items.iter().all(|item| *item == response));
+        }
+        other => panic!("unexpected results: {other:?}"),
+    }
+
+    let err = dynamic
+        .call(&mut store, "pkg:component/intf#test", vec![])
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("takes 1 arguments"), "{err}");
+}
+
+// write-file and read-file through `Dynamic`, whose futures carry a
+// `result<_, error>` and whose read-file result nests both in a tuple
+// rather than a primitive.
+#[tokio::test]
+async fn test_dynamic_payloads() {
+    use dynamic::Arg;
+    use dynamic::Dynamic;
+    use dynamic::Ret;
+    use wasmtime::component::Val;
+
+    let (instance, mut store) = instantiate(Options {
+        scratch: Some(Quota::default()),
+        ..Options::default()
+    })
+    .await;
+    let dynamic = Dynamic::new(instance);
+
+    let bytes: Vec<Val> = b"spilled state".iter().copied().map(Val::U8).collect();
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#write-file",
+            vec![
+                Arg::Value(Val::String("scratch/s.bin".to_owned())),
+                Arg::Stream(bytes.clone()),
+            ],
+        )
+        .await
+        .unwrap();
+    assert_eq!(results, [Ret::Future(Some(Val::Result(Ok(None))))]);
+
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#write-file",
+            vec![
+                Arg::Value(Val::String("nowhere/s.bin".to_owned())),
+                Arg::Stream(bytes.clone()),
+            ],
+        )
+        .await
+        .unwrap();
+    let error = Val::Variant(
+        "no-such-preopen".to_owned(),
+        Some(Box::new(Val::String("nowhere".to_owned()))),
+    );
+    assert_eq!(
+        results,
+        [Ret::Future(Some(Val::Result(Err(Some(Box::new(error))))))]
+    );
+
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#read-file",
+            vec![Arg::Value(Val::String("scratch/s.bin".to_owned()))],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        results,
+        [Ret::Tuple(vec![
+            Ret::Stream(bytes),
+            Ret::Future(Some(Val::Result(Ok(None)))),
+        ])]
+    );
+
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#read-file",
+            vec![Arg::Value(Val::String("scratch/missing.bin".to_owned()))],
+        )
+        .await
+        .unwrap();
+    let [Ret::Tuple(fields)] = &results[..] else {
+        panic!("expected one tuple, got {results:?}");
+    };
+    assert_eq!(fields[0], Ret::Stream(Vec::new()));
+    assert!(
+        matches!(
+            &fields[1],
+            Ret::Future(Some(Val::Result(Err(Some(error)))))
+                if matches!(&**error, Val::Variant(case, _) if case == "filesystem")
+        ),
+        "{fields:?}"
+    );
+}
+
+/// Generates an arbitrary value of type `ty`, using `resource` for handles.
+#[cfg(test)]
+fn arbitrary(
+    ty: &Type,
+    rng: &mut rand_chacha::ChaCha8Rng,
+    resource: wasmtime::component::ResourceAny,
+) -> wasmtime::component::Val {
+    use rand_chacha::rand_core::RngCore;
+    use wasmtime::component::Val;
+
+    fn below(rng: &mut rand_chacha::ChaCha8Rng, n: usize) -> usize {
+        rng.next_u32() as usize % n.max(1)
+    }
+
+    match ty {
+        Type::Bool => Val::Bool(below(rng, 2) == 0),
+        Type::S8 => Val::S8(rng.next_u32() as i8),
+        Type::U8 => Val::U8(rng.next_u32() as u8),
+        Type::S16 => Val::S16(rng.next_u32() as i16),
+        Type::U16 => Val::U16(rng.next_u32() as u16),
+        Type::S32 => Val::S32(rng.next_u32() as i32),
+        Type::U32 => Val::U32(rng.next_u32()),
+        Type::S64 => Val::S64(rng.next_u64() as i64),
+        Type::U64 => Val::U64(rng.next_u64()),
+        Type::Float32 => Val::Float32(rng.next_u32() as f32 / 7.0),
+        Type::Float64 => Val::Float64(rng.next_u64() as f64 / 7.0),
+        Type::Char => Val::Char(char::from_u32(rng.next_u32() % 0xD800).unwrap()),
+        Type::String => {
+            let len = below(rng, 16);
+            Val::String(
+                (0..len)
+                    .map(|_| char::from_u32(rng.next_u32() % 0xD800).unwrap())
+                    .collect(),
+            )
+        }
+        Type::List(list) => {
+            let len = below(rng, 4);
+            Val::List(
+                (0..len)
+                    .map(|_| arbitrary(&list.ty(), rng, resource))
+                    .collect(),
+            )
+        }
+        Type::Record(record) => Val::Record(
+            record
+                .fields()
+                .map(|field| (field.name.to_owned(), arbitrary(&field.ty, rng, resource)))
+                .collect(),
+        ),
+        Type::Tuple(tuple) => Val::Tuple(
+            tuple
+                .types()
+                .map(|ty| arbitrary(&ty, rng, resource))
+                .collect(),
+        ),
+        Type::Variant(variant) => {
+            let case = variant
+                .cases()
+                .nth(below(rng, variant.cases().len()))
+                .unwrap();
+            let payload = case.ty.map(|ty| Box::new(arbitrary(&ty, rng, resource)));
+            Val::Variant(case.name.to_owned(), payload)
+        }
+        Type::Enum(enum_) => Val::Enum(
+            enum_
+                .names()
+                .nth(below(rng, enum_.names().len()))
+                .unwrap()
+                .to_owned(),
+        ),
+        Type::Option(option) => match below(rng, 2) {
+            0 => Val::Option(None),
+            _ => Val::Option(Some(Box::new(arbitrary(&option.ty(), rng, resource)))),
+        },
+        Type::Result(result) => {
+            let ok = below(rng, 2) == 0;
+            let ty = if ok { result.ok() } else { result.err() };
+            let payload = ty.map(|ty| Box::new(arbitrary(&ty, rng, resource)));
+            Val::Result(if ok { Ok(payload) } else { Err(payload) })
+        }
+        Type::Flags(flags) => Val::Flags(
+            flags
+                .names()
+                .filter(|_| rng.next_u32() % 2 == 0)
+                .map(str::to_owned)
+                .collect(),
+        ),
+        Type::Own(_) | Type::Borrow(_) => Val::Resource(resource),
+        Type::Future(_) | Type::Stream(_) | Type::ErrorContext => unreachable!(),
+    }
+}
+
+// Every type used by `pkg:component/intf` survives a trip through JSON.
+#[tokio::test]
+async fn test_json_round_trip() {
+    use dynamic::Dynamic;
+    use dynamic::Ret;
+    use rand_chacha::rand_core::SeedableRng;
+    use wasmtime::component::types::ComponentItem;
+    use wasmtime::component::Val;
+
+    let (instance, mut store, _) = init().await;
+    let results = Dynamic::new(instance)
+        .call(
+            &mut store,
+            "pkg:component/intf#[constructor]session",
+            vec![],
+        )
+        .await
+        .unwrap();
+    let [Ret::Value(Val::Resource(session))] = results[..] else {
+        panic!("unexpected results: {results:?}");
+    };
+
+    let engine = store.engine().clone();
+    let component = components::GUEST.load(&engine).unwrap();
+    let Some(ComponentItem::ComponentInstance(intf)) = component
+        .component_type()
+        .get_export(&engine, "pkg:component/intf")
+    else {
+        panic!("missing pkg:component/intf");
+    };
+    let mut types = Vec::new();
+    for (_, item) in intf.exports(&engine) {
+        if let ComponentItem::ComponentFunc(func) = item {
+            types.extend(func.params().map(|(_, ty)| ty));
+            types.extend(func.results());
+        }
+    }
+
+    let mut handles = json::Handles::default();
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(Deterministic::from_env().unwrap().seed);
+    while let Some(ty) = types.pop() {
+        let payload = match &ty {
+            Type::Future(future) => future.ty(),
+            Type::Stream(stream) => stream.ty(),
+            // e.g. read-file's `tuple<stream<u8>, future<..>>`
+            Type::Tuple(tuple)
+                if tuple
+                    .types()
+                    .any(|ty| matches!(ty, Type::Future(_) | Type::Stream(_))) =>
+            {
+                types.extend(tuple.types());
+                continue;
+            }
+            _ => {
+                for _ in 0..64 {
+                    let val = arbitrary(&ty, &mut rng, session);
+                    let json = json::from_val(&ty, &val, &mut handles).unwrap();
+                    let back = json::to_val(&ty, &json, &handles).unwrap();
+                    assert_eq!(back, val, "{json}");
+                }
+                continue;
+            }
+        };
+        let err = json::to_val(&ty, &serde_json::Value::Null, &handles).unwrap_err();
+        assert!(err.to_string().contains("no JSON form"), "{err}");
+        types.extend(payload);
+    }
+}
+
+// Unsigned integers keep their full range through JSON, including the values
+// past `i64::MAX`.
+#[test]
+fn test_json_unsigned_extremes() {
+    use wasmtime::component::Val;
+
+    let mut handles = json::Handles::default();
+    for (ty, val) in [
+        (Type::U8, Val::U8(u8::MAX)),
+        (Type::U16, Val::U16(u16::MAX)),
+        (Type::U32, Val::U32(u32::MAX)),
+        (Type::U64, Val::U64(u64::MAX)),
+        (Type::U64, Val::U64(0)),
+    ] {
+        let json = json::from_val(&ty, &val, &mut handles).unwrap();
+        assert_eq!(json::to_val(&ty, &json, &handles).unwrap(), val, "{json}");
+    }
+    let err = json::to_val(&Type::U8, &serde_json::json!(256), &handles).unwrap_err();
+    assert!(err.to_string().contains("out of range"), "{err}");
+    assert!(json::to_val(&Type::U64, &serde_json::json!(-1), &handles).is_err());
+}
+
+// Scrape `/metrics` after instantiating and calling `test` with fuel enabled.
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let (instance, mut store, intf_export) = init_with(Options {
+        fuel: Some(u64::MAX),
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "test")
+        .unwrap();
+    let func: TypedFunc<(String,), (String,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    call(&mut store, "test", &func, ("Hello".to_owned(),))
+        .await
+        .unwrap();
+
+    let (addr, server) = metrics::serve("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let response = reqwest::get(format!("http://{addr}/metrics"))
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let body = response.text().await.unwrap();
+    for metric in [
+        "host_instantiation_seconds_count",
+        r#"host_call_seconds_count{export="test"}"#,
+        r#"host_fuel_consumed_total{export="test"}"#,
+        "host_memory_high_water_bytes",
+        "host_live_streams",
+        "host_cache_requests_total",
+    ] {
+        assert!(body.contains(metric), "missing {metric} in:\n{body}");
+    }
+
+    let response = reqwest::get(format!("http://{addr}/other")).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    server.abort();
+}
+
+// One Firefox profiler JSON file per call when profiling per call.
+#[tokio::test]
+async fn test_guest_profile() {
+    let dir = tempfile::tempdir().unwrap();
+    let (instance, mut store, intf_export) = init_with(Options {
+        profiling: Profiling::Guest {
+            interval: Duration::from_millis(1),
+            scope: profiling::Scope::Call,
+            dir: dir.path().to_path_buf(),
+        },
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "test")
+        .unwrap();
+    let func: TypedFunc<(String,), (String,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    for _ in 0..2 {
+        call(&mut store, "test", &func, ("Hello".to_owned(),))
+            .await
+            .unwrap();
+    }
+
+    let profiles: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(profiles.len(), 2, "{profiles:?}");
+    for path in profiles {
+        assert!(path
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("test-"));
+        let profile: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert!(profile["meta"].is_object(), "{profile}");
+    }
+}
+
+// Every guest crate in `package.metadata.guests` was built into a component
+// and, with the `precompile` feature, precompiled for the engine `compile`
+// creates by default, so loading it skips compilation.
+#[tokio::test]
+async fn test_components() {
+    let engine = compile(&Options::default()).unwrap().engine().clone();
+
+    assert_eq!(components::get("guest"), Some(&components::GUEST));
+    assert_eq!(
+        components::PRECOMPILED_FOR.is_some(),
+        cfg!(feature = "precompile")
+    );
+    for guest in components::COMPONENTS {
+        let precompiled = guest
+            .deserialize(&engine)
+            .unwrap_or_else(|err| panic!("`{}` was rejected by this engine: {err:?}", guest.name));
+        assert_eq!(
+            precompiled.is_some(),
+            cfg!(feature = "precompile"),
+            "`{}` was not precompiled for this engine",
+            guest.name
+        );
+        Component::new(&engine, guest.wasm)
+            .unwrap_or_else(|err| panic!("failed to compile `{}`: {err:?}", guest.name));
+    }
+
+    // Fuel changes the generated code, so the precompiled guest is skipped.
+    let fueled = compile(&Options {
+        fuel: Some(1),
+        ..Options::default()
+    })
+    .unwrap();
+    assert!(components::GUEST
+        .deserialize(fueled.engine())
+        .unwrap()
+        .is_none());
+}
+
+// pkg:query/query#top-bids in `pipeline`, where `query` calls into `parser`
+#[tokio::test]
+async fn test_composed_pipeline() {
+    use dynamic::Arg;
+    use dynamic::Dynamic;
+    use dynamic::Ret;
+    use wasmtime::component::Val;
+
+    let (instance, mut store) = instantiate(Options {
+        component: ComponentSource::Embedded(&components::PIPELINE),
+        ..Options::default()
+    })
+    .await;
+    let lines = format!("not,a,bid\n{BIDS_FIXTURE}")
+        .lines()
+        .map(|line| Val::String(line.to_owned()))
+        .collect();
+
+    let results = Dynamic::new(instance)
+        .call(
+            &mut store,
+            "pkg:query/query#top-bids",
+            vec![Arg::Stream(lines), Arg::Value(Val::U32(1))],
+        )
+        .await
+        .unwrap();
+
+    let [Ret::Value(Val::List(bids))] = &results[..] else {
+        panic!("unexpected results: {results:?}");
+    };
+    let [Val::Record(fields)] = &bids[..] else {
+        panic!("expected one bid: {bids:?}");
+    };
+    assert!(fields.contains(&("price".to_owned(), Val::U64(73134520))));
+    assert!(fields.contains(&("channel".to_owned(), Val::String("channel-7568".to_owned()))));
+}
+
+// [method]session.infer and remember, which call back into
+// pkg:component/host-services
+#[tokio::test]
+async fn test_host_services() {
+    use dynamic::Arg;
+    use dynamic::Dynamic;
+    use dynamic::Ret;
+    use wasmtime::component::Val;
+
+    let services = Services::default();
+    let (instance, mut store, _) = init_with(Options {
+        services: services.clone(),
+        ..Options::default()
+    })
+    .await;
+    let dynamic = Dynamic::new(instance);
+
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#[constructor]session",
+            vec![],
+        )
+        .await
+        .unwrap();
+    let [Ret::Value(Val::Resource(session))] = results[..] else {
+        panic!("unexpected results: {results:?}");
+    };
+    let request = Val::Record(vec![(
+        "message".to_owned(),
+        Val::String("hello".to_owned()),
+    )]);
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#[method]session.infer",
+            vec![Arg::Value(Val::Resource(session)), Arg::Value(request)],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        results,
+        [Ret::Value(Val::Record(vec![(
+            "message".to_owned(),
+            Val::String("echo: hello".to_owned()),
+        )]))]
+    );
+    assert_eq!(services.get("last-prompt").as_deref(), Some(&b"hello"[..]));
+    assert_eq!(store.data().services().counter("infer"), 1);
+
+    let bytes = |bytes: &[u8]| Val::List(bytes.iter().copied().map(Val::U8).collect());
+    let expected = [
+        (&b"first"[..], Val::Option(None)),
+        (&b"second"[..], Val::Option(Some(Box::new(bytes(b"first"))))),
+    ];
+    for (value, previous) in expected {
+        let results = dynamic
+            .call(
+                &mut store,
+                "pkg:component/intf#remember",
+                vec![
+                    Arg::Value(Val::String("key".to_owned())),
+                    Arg::Value(bytes(value)),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(results, [Ret::Value(previous)]);
+    }
+    assert_eq!(services.get("key").as_deref(), Some(&b"second"[..]));
+}
+
+// count-ticks: async fn(u64, u32) -> Vec<u64>, reading
+// pkg:component/host-services#ticks until it has enough, while a test3 call
+// keeps the guest busy on the same instance
+#[tokio::test]
+async fn test_ticks() {
+    use concurrent::Calls;
+
+    let services = Services::default();
+    let (instance, mut store, intf_export) = init_with(Options {
+        deterministic: Some(Deterministic::new(0)),
+        services: services.clone(),
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "count-ticks")
+        .unwrap();
+    let count_ticks: TypedFunc<(u64, u32), (Vec<u64>,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "test3")
+        .unwrap();
+    let test3: TypedFunc<(HostFuture<String>,), (String,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+
+    // Advance virtual time whenever a ticker or the guest is waiting on it.
+    let interval = Duration::from_secs(1);
+    let clock = store.data().clock().unwrap().clone();
+    let driver = tokio::spawn(async move {
+        loop {
+            clock.timers_pending(1).await;
+            clock.advance(interval);
+        }
+    });
+
+    let (tx, rx) = instance.future::<String>(&mut store).unwrap();
+    let mut busy = Calls::new();
+    busy.push(&mut store, "test3", &test3, (rx.into(),))
+        .unwrap();
+    let mut ticks = Calls::new();
+    ticks
+        .push(
+            &mut store,
+            "count-ticks",
+            &count_ticks,
+            (interval.as_nanos() as u64, 5),
+        )
+        .unwrap();
+
+    let (_, (seqs,)) = ticks.next(&mut store).await.unwrap().unwrap();
+    assert_eq!(seqs, [0, 1, 2, 3, 4]);
+    assert_eq!(services.counter("ticks"), 5);
+    assert_eq!(busy.len(), 1);
+
+    // The guest has dropped the stream; keep running the store, through the
+    // pending test3 call, until the ticker notices and exits.
+    let release = tokio::spawn({
+        let services = services.clone();
+        async move {
+            services.tickers_reach(0).await;
+            streams::spawn_future_writer(tx, "done".to_owned())
+                .await
+                .unwrap();
+        }
+    });
+    let (_, (result,)) = busy.next(&mut store).await.unwrap().unwrap();
+    assert_eq!(result, "done");
+    assert_eq!(services.tickers(), 0);
+    release.await.unwrap();
+    driver.abort();
+}
+
+/// Counts the words of `text` in a fresh instance using `namespace`.
+#[cfg(test)]
+async fn count_words(namespace: &Namespace, text: &str) -> Result<Vec<(String, i64)>, String> {
+    let (instance, mut store, intf_export) = init_with(Options {
+        keyvalue: Some(namespace.clone()),
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "count-words")
+        .unwrap();
+    let func: TypedFunc<(String,), (Result<Vec<(String, i64)>, String>,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    let (totals,) = call(&mut store, "count-words", &func, (text.to_owned(),))
+        .await
+        .unwrap();
+    totals
+}
+
+// count-words: async fn(String) -> Result<Vec<(String, i64)>, String>
+// swap: async fn(Vec<(String, Vec<u8>)>) -> Result<Vec<Option<Vec<u8>>>, String>
+#[tokio::test]
+async fn test_keyvalue() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keyvalue.redb");
+    let db = KeyValue::open(&path).unwrap();
+    let words = |words: &[(&str, i64)]| -> Vec<(String, i64)> {
+        words.iter().map(|&(w, n)| (w.to_owned(), n)).collect()
+    };
+
+    let totals = count_words(&db.namespace("a"), "apple pear apple").await;
+    assert_eq!(
+        totals,
+        Ok(words(&[("apple", 1), ("pear", 1), ("apple", 2)]))
+    );
+    let totals = count_words(&db.namespace("b"), "apple").await;
+    assert_eq!(totals, Ok(words(&[("apple", 1)])));
+
+    let (instance, mut store, intf_export) = init_with(Options {
+        keyvalue: Some(db.namespace("a")),
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "swap")
+        .unwrap();
+    let swap: TypedFunc<(Vec<(String, Vec<u8>)>,), (Result<Vec<Option<Vec<u8>>>, String>,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    let entries = vec![
+        ("k".to_owned(), b"1".to_vec()),
+        ("m".to_owned(), b"2".to_vec()),
+    ];
+    let (previous,) = call(&mut store, "swap", &swap, (entries.clone(),))
+        .await
+        .unwrap();
+    assert_eq!(previous, Ok(vec![None, None]));
+    let (previous,) = call(&mut store, "swap", &swap, (entries,)).await.unwrap();
+    assert_eq!(previous, Ok(vec![Some(b"1".to_vec()), Some(b"2".to_vec())]));
+
+    // `words/pear` is not a counter once swapped for a string.
+    let entries = vec![("words/pear".to_owned(), b"many".to_vec())];
+    call(&mut store, "swap", &swap, (entries,)).await.unwrap();
+    drop(store);
+    let err = count_words(&db.namespace("a"), "pear").await.unwrap_err();
+    assert!(err.contains("does not hold a counter"), "{err}");
+
+    // Everything survives reopening the database.
+    drop(db);
+    let db = KeyValue::open(&path).unwrap();
+    let a = db.namespace("a");
+    assert_eq!(
+        a.get("words/apple").unwrap(),
+        Some(2i64.to_le_bytes().to_vec())
+    );
+    assert_eq!(a.get("k").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(db.namespace("b").get("k").unwrap(), None);
+    let totals = count_words(&a, "apple").await;
+    assert_eq!(totals, Ok(words(&[("apple", 3)])));
+
+    // A counter that would overflow is left as it was.
+    a.increment("max", i64::MAX).unwrap();
+    let err = a.increment("max", 1).unwrap_err();
+    assert!(format!("{err}").contains("would overflow"), "{err}");
+    assert_eq!(a.increment("max", 0).unwrap(), i64::MAX);
+}
+
+/// Feeds `lines` to `aggregate` with one-second windows.
+#[cfg(test)]
+async fn aggregate(
+    instance: &Instance,
+    store: &mut Store<Host>,
+    intf_export: &ComponentExportIndex,
+    lines: &[&str],
+) -> Vec<WindowStats> {
+    let export = instance
+        .get_export(&mut *store, Some(intf_export), "aggregate")
+        .unwrap();
+    let func: TypedFunc<(HostStream<String>, u64), (Vec<WindowStats>,)> =
+        instance.get_typed_func(&mut *store, export).unwrap();
+    let (tx, rx) = instance
+        .stream::<String, VecBuffer<String>, Vec<String>, _, _>(&mut *store)
+        .unwrap();
+    let lines = lines.iter().map(|line| line.to_string()).collect();
+    let writer = streams::spawn_writer(tx, lines, 4);
+    let (closed,) = call(store, "aggregate", &func, (rx.into(), 1))
+        .await
+        .unwrap();
+    writer.await.unwrap();
+    closed
+}
+
+#[cfg(test)]
+async fn flush(
+    instance: &Instance,
+    store: &mut Store<Host>,
+    intf_export: &ComponentExportIndex,
+) -> Vec<WindowStats> {
+    let export = instance
+        .get_export(&mut *store, Some(intf_export), "flush")
+        .unwrap();
+    let func: TypedFunc<(), (Vec<WindowStats>,)> =
+        instance.get_typed_func(&mut *store, export).unwrap();
+    let (closed,) = call(store, "flush", &func, ()).await.unwrap();
+    closed
+}
+
+/// Calls `[static]session.resume` and returns the handle, if any.
+#[cfg(test)]
+async fn resume(
+    dynamic: &dynamic::Dynamic,
+    store: &mut Store<Host>,
+    id: u64,
+) -> Option<wasmtime::component::Val> {
+    use dynamic::Arg;
+    use dynamic::Ret;
+    use wasmtime::component::Val;
+
+    let results = dynamic
+        .call(
+            store,
+            "pkg:component/intf#[static]session.resume",
+            vec![Arg::Value(Val::U64(id))],
+        )
+        .await
+        .unwrap();
+    match results.as_slice() {
+        [Ret::Value(Val::Option(session))] => session.as_deref().cloned(),
+        other => panic!("unexpected results: {other:?}"),
+    }
+}
+
+// snapshot: fn() -> Vec<u8>
+// restore: fn(Vec<u8>) -> Result<(), String>
+// A windowed query moves to a new instance in the middle of its input stream,
+// with a session alongside, and produces the same windows as running it in
+// one go.
+#[tokio::test]
+async fn test_snapshot_restore() {
+    use concurrent::Calls;
+    use dynamic::Arg;
+    use dynamic::Dynamic;
+    use dynamic::Ret;
+    use wasmtime::component::Val;
+
+    let bids =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/data/bids.csv")).unwrap();
+    let lines: Vec<&str> = bids.lines().collect();
+    let (first, second) = lines.split_at(lines.len() / 2);
+
+    let (instance, mut store, intf_export) = init().await;
+    let mut expected = aggregate(&instance, &mut store, &intf_export, &lines).await;
+    expected.extend(flush(&instance, &mut store, &intf_export).await);
+    assert_eq!(expected.len(), 3);
+    assert_eq!(
+        expected.iter().map(|window| window.bids).sum::<u64>(),
+        lines.len() as u64
+    );
+
+    let (instance, mut store, intf_export) = init().await;
+    let dynamic = Dynamic::new(instance);
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#[constructor]session",
+            vec![],
+        )
+        .await
+        .unwrap();
+    let [Ret::Value(Val::Resource(session))] = results[..] else {
+        panic!("unexpected results: {results:?}");
+    };
+    let request = Val::Record(vec![(
+        "message".to_owned(),
+        Val::String("hello".to_owned()),
+    )]);
+    dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#[method]session.infer",
+            vec![Arg::Value(Val::Resource(session)), Arg::Value(request)],
+        )
+        .await
+        .unwrap();
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#[method]session.id",
+            vec![Arg::Value(Val::Resource(session))],
+        )
+        .await
+        .unwrap();
+    let [Ret::Value(Val::U64(id))] = results[..] else {
+        panic!("unexpected results: {results:?}");
+    };
+    // The session already has a handle, so it cannot get another.
+    assert_eq!(resume(&dynamic, &mut store, id).await, None);
+
+    // Start aggregating, and feed it the first half without closing the
+    // stream. A test3 call returns once the writer is done, which keeps the
+    // store running until then.
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "aggregate")
+        .unwrap();
+    let aggregate_func: TypedFunc<(HostStream<String>, u64), (Vec<WindowStats>,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "test3")
+        .unwrap();
+    let test3: TypedFunc<(HostFuture<String>,), (String,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    let (lines_tx, lines_rx) = instance
+        .stream::<String, VecBuffer<String>, Vec<String>, _, _>(&mut store)
+        .unwrap();
+    let (done_tx, done_rx) = instance.future::<String>(&mut store).unwrap();
+    let mut aggregating = Calls::new();
+    aggregating
+        .push(
+            &mut store,
+            "aggregate",
+            &aggregate_func,
+            (lines_rx.into(), 1),
+        )
+        .unwrap();
+    let mut synced = Calls::new();
+    synced
+        .push(&mut store, "test3", &test3, (done_rx.into(),))
+        .unwrap();
+    let written: Vec<String> = first.iter().map(|line| line.to_string()).collect();
+    let writer = tokio::spawn(async move {
+        let mut tx = lines_tx;
+        // The guest skips the blank line, and only reads it once it is done
+        // with every line before it.
+        for line in written.into_iter().chain([String::new()]) {
+            let (next, _) = tx.write(VecBuffer::from(vec![line])).into_future().await;
+            tx = next.expect("the guest closed the stream");
+        }
+        done_tx.write("written".to_owned()).into_future().await;
+        tx
+    });
+    let (_, (done,)) = synced.next(&mut store).await.unwrap().unwrap();
+    assert_eq!(done, "written");
+    let lines_tx = writer.await.unwrap();
+
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "snapshot")
+        .unwrap();
+    let snapshot: TypedFunc<(), (Vec<u8>,)> = instance.get_typed_func(&mut store, export).unwrap();
+    let (bytes,) = call(&mut store, "snapshot", &snapshot, ()).await.unwrap();
+    assert_eq!(aggregating.len(), 1);
+
+    // Restoring over live sessions is refused.
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "restore")
+        .unwrap();
+    let restore: TypedFunc<(Vec<u8>,), (Result<(), String>,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    let (result,) = call(&mut store, "restore", &restore, (bytes.clone(),))
+        .await
+        .unwrap();
+    assert!(result.unwrap_err().contains("sessions are open"));
+
+    // Windows the first half closed come back from the original call.
+    drop(lines_tx);
+    let (_, (closed,)) = aggregating.next(&mut store).await.unwrap().unwrap();
+    let mut windows = closed;
+    drop(store);
+
+    let (instance, mut store, intf_export) = init().await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "restore")
+        .unwrap();
+    let restore: TypedFunc<(Vec<u8>,), (Result<(), String>,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+    let (result,) = call(&mut store, "restore", &restore, (b"{}".to_vec(),))
+        .await
+        .unwrap();
+    assert!(result.unwrap_err().contains("invalid snapshot"));
+    let (result,) = call(&mut store, "restore", &restore, (bytes.clone(),))
+        .await
+        .unwrap();
+    result.unwrap();
+    windows.extend(aggregate(&instance, &mut store, &intf_export, second).await);
+    windows.extend(flush(&instance, &mut store, &intf_export).await);
+    assert_eq!(windows, expected);
+
+    let dynamic = Dynamic::new(instance);
+    let session = resume(&dynamic, &mut store, id).await.unwrap();
+    assert_eq!(resume(&dynamic, &mut store, id).await, None);
+    let results = dynamic
+        .call(
+            &mut store,
+            "pkg:component/intf#[method]session.last-response",
+            vec![Arg::Value(session)],
+        )
+        .await
+        .unwrap();
+    assert_eq!(results, [Ret::Value(Val::String("echo: hello".to_owned()))]);
+    let (result,) = call(&mut store, "restore", &restore, (bytes,))
+        .await
+        .unwrap();
+    assert!(result.unwrap_err().contains("sessions are open"));
+}
+
+// Replacing the component mid-call: the in-flight `throttle` finishes on the
+// version it started on, and later calls go to the new one.
+#[tokio::test]
+async fn test_hot_reload() {
+    use dynamic::Arg;
+    use dynamic::Ret;
+    use reload::Reloader;
+    use wasmtime::component::Val;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("guest.component.wasm");
+    std::fs::write(&path, components::GUEST.wasm).unwrap();
+    let reloader = Arc::new(
+        Reloader::new(&path, Duration::from_millis(10), || Options {
+            deterministic: Some(Deterministic::new(0)),
+            ..Options::default()
+        })
+        .await
+        .unwrap(),
+    );
+    let first = reloader.current();
+    assert_eq!(first.number, 1);
+    let clock = first.clock().unwrap();
+
+    let items = (0..3).map(|i| Val::String(format!("item-{i}"))).collect();
+    let mut in_flight = tokio::spawn({
+        let reloader = reloader.clone();
+        async move {
+            let args = vec![
+                Arg::Stream(items),
+                Arg::Value(Val::U64(Duration::from_secs(1).as_nanos() as u64)),
+            ];
+            reloader
+                .call("pkg:component/intf#throttle", args)
+                .await
+                .unwrap()
+        }
+    });
+    clock.timers_pending(1).await;
+
+    let staged = dir.path().join("pipeline.component.wasm");
+    std::fs::write(&staged, components::PIPELINE.wasm).unwrap();
+    std::fs::rename(&staged, &path).unwrap();
+    let deadline = std::time::Instant::now() + Duration::from_secs(60);
+    while reloader.current().number == 1 {
+        assert!(std::time::Instant::now() < deadline, "no reload");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let served = reloader
+        .call(
+            "pkg:query/query#top-bids",
+            vec![
+                Arg::Stream(vec![Val::String(
+                    BIDS_FIXTURE.lines().next().unwrap().to_owned(),
+                )]),
+                Arg::Value(Val::U32(1)),
+            ],
+        )
+        .await
+        .unwrap();
+    assert_eq!(served.version, 2);
+    assert!(!in_flight.is_finished());
+
+    let served = loop {
+        tokio::select! {
+            served = &mut in_flight => break served.unwrap(),
+            () = clock.timers_pending(1) => clock.advance(Duration::from_secs(1)),
+        }
+    };
+    assert_eq!(served.version, 1);
+    let items: Vec<_> = (0..3).map(|i| Val::String(format!("item-{i}"))).collect();
+    assert_eq!(served.results, [Ret::Stream(items)]);
+}
+
+// test3 several times on one instance: every call is in flight before any
+// future is written, and each call finishes as soon as its own future is.
+#[tokio::test]
+async fn test_concurrent_calls() {
+    use concurrent::Calls;
+
+    let (instance, mut store, intf_export) = init().await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "test3")
+        .unwrap();
+    let func3: TypedFunc<(HostFuture<String>,), (String,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+
+    let mut calls = Calls::new();
+    let mut writers = Vec::new();
+    for i in 0..4 {
+        let (tx, rx) = instance.future::<String>(&mut store).unwrap();
+        let index = calls
+            .push(&mut store, "test3", &func3, (rx.into(),))
+            .unwrap();
+        assert_eq!(index, i);
+        writers.push(tx);
+    }
+    assert_eq!(calls.len(), 4);
+
+    for (i, tx) in writers.into_iter().enumerate().rev() {
+        let writer = streams::spawn_future_writer(tx, format!("message-{i}"));
+        let (index, (result,)) = calls.next(&mut store).await.unwrap().unwrap();
+        assert_eq!(index, i);
+        assert_eq!(result, format!("message-{i}"));
+        writer.await.unwrap();
+    }
+    assert!(calls.is_empty());
+    assert!(calls.next(&mut store).await.unwrap().is_none());
+}
+
+// [method]session.infer on several sessions at once, against a model whose
+// answers the test releases one at a time, out of order.
+#[tokio::test]
+async fn test_concurrent_infer() {
+    use concurrent::Calls;
+    use std::future::Future;
+    use std::pin::Pin;
+    use tokio::sync::oneshot;
+    use wasmtime::component::ResourceAny;
+
+    /// Answers each prompt with what the test sends for it.
+    struct Gated(Mutex<HashMap<String, oneshot::Receiver<String>>>);
+
+    impl services::Model for Gated {
+        fn infer(&self, prompt: String) -> Pin<Box<dyn Future<Output = Result<String>> + Send>> {
+            let answer = self.0.lock().unwrap().remove(&prompt);
+            Box::pin(async move {
+                let answer = answer.ok_or_else(|| anyhow::anyhow!("unexpected {prompt}"))?;
+                Ok(answer.await?)
+            })
+        }
+    }
+
+    let mut answers = HashMap::new();
+    let mut gates = HashMap::new();
+    for i in 0..4 {
+        let (tx, rx) = oneshot::channel();
+        answers.insert(i, tx);
+        gates.insert(format!("prompt-{i}"), rx);
+    }
+    let (instance, mut store, intf_export) = init_with(Options {
+        services: Services::new(Arc::new(Gated(Mutex::new(gates)))),
+        ..Options::default()
+    })
+    .await;
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "[constructor]session")
+        .unwrap();
+    let new: TypedFunc<(), (ResourceAny,)> = instance.get_typed_func(&mut store, export).unwrap();
+    let export = instance
+        .get_export(&mut store, Some(&intf_export), "[method]session.infer")
+        .unwrap();
+    let infer: TypedFunc<(ResourceAny, Request), (Response,)> =
+        instance.get_typed_func(&mut store, export).unwrap();
+
+    let mut calls = Calls::new();
+    for i in 0..4 {
+        let (session,) = call(&mut store, "[constructor]session", &new, ())
+            .await
+            .unwrap();
+        let request = Request {
+            message: format!("prompt-{i}"),
+        };
+        calls
+            .push(
+                &mut store,
+                "[method]session.infer",
+                &infer,
+                (session, request),
+            )
+            .unwrap();
+    }
+
+    // Each call can only finish once its answer is sent, so `next` has to
+    // return exactly the call that was just released.
+    for i in [3, 1] {
+        answers
+            .remove(&i)
+            .unwrap()
+            .send(format!("answer-{i}"))
+            .unwrap();
+        let (index, (response,)) = calls.next(&mut store).await.unwrap().unwrap();
+        assert_eq!(index, i);
+        assert_eq!(response.message, format!("answer-{i}"));
+    }
+
+    // The rest keep the indices they were started under.
+    for i in [2, 0] {
+        answers
+            .remove(&i)
+            .unwrap()
+            .send(format!("answer-{i}"))
+            .unwrap();
+    }
+    let rest: Vec<_> = calls
+        .join(&mut store)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(index, (response,))| (index, response.message))
+        .collect();
+    assert_eq!(
+        rest,
+        [(0, "answer-0".to_owned()), (2, "answer-2".to_owned())]
+    );
+    assert_eq!(store.data().services().counter("infer"), 4);
+}
+
+// count-words through a pool: every call for a key lands on the same worker,
+// whose in-memory key-value store keeps the running total.
+#[tokio::test]
+async fn test_pool_affinity() {
+    use dynamic::Arg;
+    use dynamic::Ret;
+    use pool::Pool;
+    use wasmtime::component::Val;
+
+    let pool = Arc::new(Pool::new(4, Options::default).await.unwrap());
+    assert_eq!(pool.len(), 4);
+
+    let mut sessions = tokio::task::JoinSet::new();
+    for key in ["alice", "bob", "carol", "dave", "erin"] {
+        let pool = pool.clone();
+        sessions.spawn(async move {
+            for total in 1..=3 {
+                let args = vec![Arg::Value(Val::String(key.to_owned()))];
+                let results = pool
+                    .call(key, "pkg:component/intf#count-words", args)
+                    .await
+                    .unwrap();
+                let expected = Val::List(vec![Val::Tuple(vec![
+                    Val::String(key.to_owned()),
+                    Val::S64(total),
+                ])]);
+                assert_eq!(
+                    results,
+                    [Ret::Value(Val::Result(Ok(Some(Box::new(expected)))))]
+                );
+            }
+        });
+    }
+    sessions.join_all().await;
+
+    let Ok(pool) = Arc::try_unwrap(pool) else {
+        panic!("the pool is still shared");
+    };
+    pool.shutdown().await.unwrap();
+}
+
+// A call that traps, here by running out of fuel, is reported to its caller,
+// and the next call with the same key gets a fresh instance. The same holds
+// for a reloader's version.
+#[tokio::test]
+async fn test_trap_recovery() {
+    use dynamic::Arg;
+    use dynamic::Ret;
+    use pool::Pool;
+    use reload::Reloader;
+    use wasmtime::component::Val;
+
+    // Plenty for instantiating and calling `test`, far too little for
+    // aggregating this many bids.
+    let options = || Options {
+        fuel: Some(10_000_000),
+        ..Options::default()
+    };
+    let line = BIDS_FIXTURE.lines().next().unwrap();
+    let bids = || {
+        vec![
+            Arg::Stream(vec![Val::String(line.to_owned()); 100_000]),
+            Arg::Value(Val::U64(1)),
+        ]
+    };
+    let hello = [Ret::Value(Val::String("Hello World! (test1)".to_owned()))];
+    let is_out_of_fuel = |err: &anyhow::Error| {
+        err.downcast_ref::<wasmtime::Trap>() == Some(&wasmtime::Trap::OutOfFuel)
+    };
+
+    let pool = Pool::new(2, options).await.unwrap();
+    for _ in 0..2 {
+        let err = pool
+            .call("alice", "pkg:component/intf#aggregate", bids())
+            .await
+            .unwrap_err();
+        assert!(is_out_of_fuel(&err), "{err:?}");
+        let args = vec![Arg::Value(Val::String("alice".to_owned()))];
+        let results = pool
+            .call("alice", "pkg:component/intf#test", args)
+            .await
+            .unwrap();
+        assert_eq!(results, hello);
+    }
+    pool.shutdown().await.unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("guest.component.wasm");
+    std::fs::write(&path, components::GUEST.wasm).unwrap();
+    let reloader = Reloader::new(&path, Duration::from_secs(60), options)
+        .await
+        .unwrap();
+    let err = reloader
+        .call("pkg:component/intf#aggregate", bids())
+        .await
+        .unwrap_err();
+    assert!(is_out_of_fuel(&err), "{err:?}");
+    let args = vec![Arg::Value(Val::String("alice".to_owned()))];
+    let served = reloader
+        .call("pkg:component/intf#test", args)
+        .await
+        .unwrap();
+    assert_eq!((served.version, served.results), (1, hello.to_vec()));
+}
+
+// Throughput of `test` with one worker and with one per core:
+//
+//     cargo test --release test_pool_throughput -- --ignored --nocapture
+#[tokio::test]
+#[ignore]
+async fn test_pool_throughput() {
+    use dynamic::Arg;
+    use pool::Pool;
+    use wasmtime::component::Val;
+
+    const CALLS: usize = 20_000;
+
+    async fn throughput(workers: usize) -> f64 {
+        let pool = Arc::new(Pool::new(workers, Options::default).await.unwrap());
+        let start = std::time::Instant::now();
+        let mut calls = tokio::task::JoinSet::new();
+        for i in 0..CALLS {
+            let pool = pool.clone();
+            calls.spawn(async move {
+                let args = vec![Arg::Value(Val::String("Hello".to_owned()))];
+                pool.call(&format!("session-{i}"), "pkg:component/intf#test", args)
+                    .await
+                    .unwrap();
+            });
+        }
+        calls.join_all().await;
+        CALLS as f64 / start.elapsed().as_secs_f64()
+    }
+
+    let cores = std::thread::available_parallelism().unwrap().get();
+    let single = throughput(1).await;
+    let scaled = throughput(cores).await;
+    println!("1 worker: {single:.0} calls/s");
+    println!(
+        "{cores} workers: {scaled:.0} calls/s ({:.1}x)",
+        scaled / single
+    );
+    if cores >= 4 {
+        assert!(scaled > 1.5 * single, "no scaling across {cores} cores");
+    }
 }
@@ -0,0 +1,357 @@
+//! Command-line front end for guest components.
+//!
+//! ```text
+//! host exports
+//! host call 'pkg:component/intf#test' '"hello"'
+//! host call --json 'pkg:component/intf#sample' 16
+//! host call 'pkg:component/intf#test3' @message.txt
+//! host call 'pkg:component/intf#test4' - < lines.txt
+//! host run < data/bids.csv
+//! ```
+//!
+//! Arguments are WAVE values, or JSON with `--json`, one per parameter.
+//! `future` and `stream` parameters instead take `@path` to read their
+//! contents from a file, or `-` to read them from stdin.
+
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use clap::Parser;
+use clap::Subcommand;
+use host::dynamic::Arg;
+use host::dynamic::Dynamic;
+use host::dynamic::Ret;
+use host::json;
+use host::json::Handles;
+use host::profiling::Profiling;
+use host::ComponentSource;
+use host::Host;
+use host::Options;
+use wasmtime::component::types::ComponentItem;
+use wasmtime::component::Component;
+use wasmtime::component::Instance;
+use wasmtime::component::Type;
+use wasmtime::component::Val;
+use wasmtime::Store;
+
+#[derive(Parser)]
+#[command(about = "Load a guest component and call its exports")]
+struct Cli {
+    /// Component to load instead of the `guest` crate: a path, or the name of
+    /// another guest crate built by this crate.
+    #[arg(long, global = true)]
+    component: Option<PathBuf>,
+    /// Serve Prometheus metrics at `http://<ADDR>/metrics` while running.
+    #[arg(long, global = true, value_name = "ADDR")]
+    metrics: Option<SocketAddr>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the component's exported functions and their types.
+    Exports,
+    /// Call an exported function, e.g. `pkg:component/intf#test`.
+    Call {
+        export: String,
+        /// One value per parameter, or `@path`/`-` for futures and streams.
+        args: Vec<String>,
+        /// Read and print values as JSON instead of WAVE.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run the component's `wasi:cli/run` export with inherited stdio.
+    Run,
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    host::telemetry::install();
+    let cli = Cli::parse();
+    if let Some(addr) = cli.metrics {
+        let (addr, _) = host::metrics::serve(addr).await?;
+        eprintln!("serving metrics at http://{addr}/metrics");
+    }
+    // A guest's name loads the copy embedded in the binary, unless a file of
+    // that name exists.
+    let component = match cli.component {
+        None => ComponentSource::default(),
+        Some(path) => match host::components::get(&path.to_string_lossy()) {
+            Some(guest) if !path.exists() => ComponentSource::Embedded(guest),
+            _ => ComponentSource::Path(path),
+        },
+    };
+    let options = Options {
+        component,
+        profiling: Profiling::from_env()?,
+        ..Options::default()
+    };
+    let component = host::compile(&options)?;
+    let (instance, mut store) = host::try_instantiate_compiled(&component, options).await?;
+
+    let code = match cli.command {
+        Command::Exports => list_exports(&component, &store),
+        Command::Call { export, args, json } => {
+            let codec = Codec {
+                json,
+                handles: Handles::default(),
+            };
+            call(&instance, &mut store, &export, &args, codec).await
+        }
+        Command::Run => match host::command::run(&instance, &mut store).await {
+            Ok(Ok(())) => Ok(ExitCode::SUCCESS),
+            Ok(Err(())) => Ok(ExitCode::FAILURE),
+            Err(err) => Err(err),
+        },
+    };
+    host::telemetry::shutdown();
+    code
+}
+
+fn list_exports(component: &Component, store: &Store<Host>) -> Result<ExitCode> {
+    let engine = store.engine();
+    for (name, item) in component.component_type().exports(engine) {
+        match item {
+            ComponentItem::ComponentFunc(func) => {
+                println!("{name}: {}", describe_func(func.params(), func.results()));
+            }
+            ComponentItem::ComponentInstance(instance) => {
+                for (func_name, item) in instance.exports(engine) {
+                    if let ComponentItem::ComponentFunc(func) = item {
+                        let signature = describe_func(func.params(), func.results());
+                        println!("{name}#{func_name}: {signature}");
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn call(
+    instance: &Instance,
+    store: &mut Store<Host>,
+    export: &str,
+    args: &[String],
+    mut codec: Codec,
+) -> Result<ExitCode> {
+    let dynamic = Dynamic::new(*instance);
+    let signature = dynamic.signature(store, export)?;
+    ensure!(
+        signature.params.len() == args.len(),
+        "`{export}` takes {} arguments but {} were given",
+        signature.params.len(),
+        args.len()
+    );
+
+    let mut values = Vec::with_capacity(args.len());
+    for ((name, ty), arg) in signature.params.iter().zip(args) {
+        let value = codec
+            .parse_arg(ty, arg)
+            .with_context(|| format!("invalid argument `{name}`"))?;
+        values.push(value);
+    }
+
+    let results = dynamic.call(store, export, values).await?;
+    for (ty, result) in signature.results.iter().zip(results) {
+        codec.print_result(ty, result)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Reads and writes values as WAVE or JSON.
+///
+/// `string` payloads of futures and streams are plain text in either format,
+/// and `stream<u8>` is raw bytes.
+struct Codec {
+    json: bool,
+    handles: Handles,
+}
+
+impl Codec {
+    fn parse(&self, ty: &Type, text: &str) -> Result<Val> {
+        if self.json {
+            json::to_val(ty, &serde_json::from_str(text)?, &self.handles)
+        } else {
+            Ok(wasm_wave::from_str::<Val>(ty, text)?)
+        }
+    }
+
+    fn print(&mut self, ty: &Type, value: &Val) -> Result<()> {
+        if self.json {
+            println!("{}", json::from_val(ty, value, &mut self.handles)?);
+        } else {
+            println!("{}", wasm_wave::to_string(value)?);
+        }
+        Ok(())
+    }
+
+    fn parse_arg(&self, ty: &Type, arg: &str) -> Result<Arg> {
+        match ty {
+            Type::Future(future) => {
+                let payload = future
+                    .ty()
+                    .context("futures without a payload are unsupported")?;
+                let contents = String::from_utf8(read_source(arg)?)?;
+                let value = match payload {
+                    Type::String => Val::String(contents),
+                    payload => self.parse(&payload, contents.trim())?,
+                };
+                Ok(Arg::Future(value))
+            }
+            Type::Stream(stream) => {
+                let payload = stream
+                    .ty()
+                    .context("streams without a payload are unsupported")?;
+                let contents = read_source(arg)?;
+                let items = match payload {
+                    Type::U8 => contents.into_iter().map(Val::U8).collect(),
+                    Type::String => String::from_utf8(contents)?
+                        .lines()
+                        .map(|line| Val::String(line.to_owned()))
+                        .collect(),
+                    payload => String::from_utf8(contents)?
+                        .lines()
+                        .map(|line| self.parse(&payload, line))
+                        .collect::<Result<_>>()?,
+                };
+                Ok(Arg::Stream(items))
+            }
+            _ => Ok(Arg::Value(self.parse(ty, arg)?)),
+        }
+    }
+
+    /// Prints values one per line, and byte streams to stdout as is. The
+    /// fields of a drained tuple are printed in turn.
+    fn print_result(&mut self, ty: &Type, result: Ret) -> Result<()> {
+        match (ty, result) {
+            (Type::Future(future), Ret::Future(Some(value))) => {
+                self.print(&future.ty().unwrap(), &value)?;
+            }
+            (_, Ret::Future(None)) => println!("<future closed>"),
+            (Type::Stream(stream), Ret::Stream(items)) => {
+                let payload = stream.ty().unwrap();
+                if let Type::U8 = payload {
+                    let bytes: Vec<u8> = items
+                        .into_iter()
+                        .map(|item| match item {
+                            Val::U8(byte) => byte,
+                            _ => unreachable!(),
+                        })
+                        .collect();
+                    std::io::stdout().write_all(&bytes)?;
+                } else {
+                    for item in items {
+                        self.print(&payload, &item)?;
+                    }
+                }
+            }
+            (Type::Tuple(tuple), Ret::Tuple(fields)) => {
+                for (ty, field) in tuple.types().zip(fields) {
+                    self.print_result(&ty, field)?;
+                }
+            }
+            (ty, Ret::Value(value)) => self.print(ty, &value)?,
+            (ty, result) => bail!("unexpected {result:?} for {}", describe(ty)),
+        }
+        Ok(())
+    }
+}
+
+/// Reads `@path` from a file and `-` from stdin.
+fn read_source(arg: &str) -> Result<Vec<u8>> {
+    if arg == "-" {
+        let mut contents = Vec::new();
+        std::io::stdin().read_to_end(&mut contents)?;
+        Ok(contents)
+    } else if let Some(path) = arg.strip_prefix('@') {
+        std::fs::read(path).with_context(|| format!("failed to read {path}"))
+    } else {
+        bail!("expected `@path` or `-`, found {arg:?}")
+    }
+}
+
+fn describe_func<'a>(
+    params: impl Iterator<Item = (&'a str, Type)>,
+    results: impl Iterator<Item = Type>,
+) -> String {
+    let params: Vec<String> = params
+        .map(|(name, ty)| format!("{name}: {}", describe(&ty)))
+        .collect();
+    let results: Vec<String> = results.map(|ty| describe(&ty)).collect();
+    match &results[..] {
+        [] => format!("func({})", params.join(", ")),
+        [result] => format!("func({}) -> {result}", params.join(", ")),
+        _ => format!("func({}) -> ({})", params.join(", "), results.join(", ")),
+    }
+}
+
+/// Renders a type roughly as it would be written in WIT.
+fn describe(ty: &Type) -> String {
+    let optional = |ty: Option<Type>| ty.map(|ty| describe(&ty)).unwrap_or_else(|| "_".into());
+    match ty {
+        Type::Bool => "bool".into(),
+        Type::S8 => "s8".into(),
+        Type::U8 => "u8".into(),
+        Type::S16 => "s16".into(),
+        Type::U16 => "u16".into(),
+        Type::S32 => "s32".into(),
+        Type::U32 => "u32".into(),
+        Type::S64 => "s64".into(),
+        Type::U64 => "u64".into(),
+        Type::Float32 => "f32".into(),
+        Type::Float64 => "f64".into(),
+        Type::Char => "char".into(),
+        Type::String => "string".into(),
+        Type::List(list) => format!("list<{}>", describe(&list.ty())),
+        Type::Record(record) => {
+            let fields: Vec<String> = record
+                .fields()
+                .map(|field| format!("{}: {}", field.name, describe(&field.ty)))
+                .collect();
+            format!("record {{ {} }}", fields.join(", "))
+        }
+        Type::Tuple(tuple) => {
+            let types: Vec<String> = tuple.types().map(|ty| describe(&ty)).collect();
+            format!("tuple<{}>", types.join(", "))
+        }
+        Type::Variant(variant) => {
+            let cases: Vec<String> = variant
+                .cases()
+                .map(|case| match case.ty {
+                    Some(ty) => format!("{}({})", case.name, describe(&ty)),
+                    None => case.name.to_owned(),
+                })
+                .collect();
+            format!("variant {{ {} }}", cases.join(", "))
+        }
+        Type::Enum(enum_) => format!(
+            "enum {{ {} }}",
+            enum_.names().collect::<Vec<_>>().join(", ")
+        ),
+        Type::Option(option) => format!("option<{}>", describe(&option.ty())),
+        Type::Result(result) => format!(
+            "result<{}, {}>",
+            optional(result.ok()),
+            optional(result.err())
+        ),
+        Type::Flags(flags) => format!(
+            "flags {{ {} }}",
+            flags.names().collect::<Vec<_>>().join(", ")
+        ),
+        Type::Own(_) => "own<resource>".into(),
+        Type::Borrow(_) => "borrow<resource>".into(),
+        Type::Future(future) => format!("future<{}>", optional(future.ty())),
+        Type::Stream(stream) => format!("stream<{}>", optional(stream.ty())),
+        Type::ErrorContext => "error-context".into(),
+    }
+}
@@ -0,0 +1,267 @@
+//! Prometheus metrics for the host runtime, served from `/metrics` by
+//! [`serve`].
+//!
+//! | Metric                               | Kind      | Labels      |
+//! |--------------------------------------|-----------|-------------|
+//! | `host_instantiation_seconds`         | histogram |             |
+//! | `host_call_seconds`                  | histogram | `export`    |
+//! | `host_fuel_consumed_total`           | counter   | `export`    |
+//! | `host_memory_high_water_bytes`       | gauge     |             |
+//! | `host_live_streams`                  | gauge     |             |
+//! | `host_live_futures`                  | gauge     |             |
+//! | `host_stream_items_total`            | counter   | `direction` |
+//! | `host_cache_requests_total`          | counter   | `result`    |
+//! | `host_guest_counter_total`           | counter   | `name`      |
+//!
+//! Items per second and the cache hit rate are the `rate` of the stream item
+//! and cache request counters. The guest bumps its own counters through
+//! `pkg:component/host-services#increment`.
+
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use anyhow::Result;
+use prometheus::Encoder;
+use prometheus::Histogram;
+use prometheus::HistogramOpts;
+use prometheus::HistogramVec;
+use prometheus::IntCounterVec;
+use prometheus::IntGauge;
+use prometheus::Opts;
+use prometheus::Registry;
+use prometheus::TextEncoder;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use wasmtime::ResourceLimiter;
+use wasmtime::Store;
+
+use crate::profiling;
+use crate::Host;
+
+pub struct Metrics {
+    pub registry: Registry,
+    pub instantiation: Histogram,
+    pub calls: HistogramVec,
+    pub fuel: IntCounterVec,
+    pub memory_high_water: IntGauge,
+    pub live_streams: IntGauge,
+    pub live_futures: IntGauge,
+    pub stream_items: IntCounterVec,
+    pub cache_requests: IntCounterVec,
+    pub guest_counters: IntCounterVec,
+}
+
+/// The process-wide registry every store reports to.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics::new().unwrap());
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            instantiation: Histogram::with_opts(HistogramOpts::new(
+                "host_instantiation_seconds",
+                "Time spent instantiating compiled components.",
+            ))?,
+            calls: HistogramVec::new(
+                HistogramOpts::new("host_call_seconds", "Latency of export calls."),
+                &["export"],
+            )?,
+            fuel: IntCounterVec::new(
+                Opts::new(
+                    "host_fuel_consumed_total",
+                    "Fuel consumed by export calls, when fuel is enabled.",
+                ),
+                &["export"],
+            )?,
+            memory_high_water: IntGauge::new(
+                "host_memory_high_water_bytes",
+                "Largest linear memory any instance has grown to.",
+            )?,
+            live_streams: IntGauge::new(
+                "host_live_streams",
+                "Streams the host is currently reading or writing.",
+            )?,
+            live_futures: IntGauge::new(
+                "host_live_futures",
+                "Futures the host is currently reading or writing.",
+            )?,
+            stream_items: IntCounterVec::new(
+                Opts::new(
+                    "host_stream_items_total",
+                    "Items moved through host-side streams.",
+                ),
+                &["direction"],
+            )?,
+            cache_requests: IntCounterVec::new(
+                Opts::new(
+                    "host_cache_requests_total",
+                    "Incremental compilation cache lookups.",
+                ),
+                &["result"],
+            )?,
+            guest_counters: IntCounterVec::new(
+                Opts::new(
+                    "host_guest_counter_total",
+                    "Counters incremented by the guest.",
+                ),
+                &["name"],
+            )?,
+            registry,
+        };
+        let registry = &metrics.registry;
+        registry.register(Box::new(metrics.instantiation.clone()))?;
+        registry.register(Box::new(metrics.calls.clone()))?;
+        registry.register(Box::new(metrics.fuel.clone()))?;
+        registry.register(Box::new(metrics.memory_high_water.clone()))?;
+        registry.register(Box::new(metrics.live_streams.clone()))?;
+        registry.register(Box::new(metrics.live_futures.clone()))?;
+        registry.register(Box::new(metrics.stream_items.clone()))?;
+        registry.register(Box::new(metrics.cache_requests.clone()))?;
+        registry.register(Box::new(metrics.guest_counters.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+
+    /// Raises the memory high-water mark to `bytes` if it is lower.
+    pub(crate) fn memory_grown(&self, bytes: usize) {
+        let bytes = bytes as i64;
+        if bytes > self.memory_high_water.get() {
+            self.memory_high_water.set(bytes);
+        }
+    }
+}
+
+/// Decrements a live stream or future gauge when dropped.
+pub(crate) struct Live(&'static IntGauge);
+
+impl Live {
+    pub(crate) fn stream() -> Self {
+        Live::new(&METRICS.live_streams)
+    }
+
+    pub(crate) fn future() -> Self {
+        Live::new(&METRICS.live_futures)
+    }
+
+    fn new(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        Live(gauge)
+    }
+}
+
+impl Drop for Live {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Serves [`METRICS`] at `GET /metrics` on `addr`. Returns the bound address,
+/// which is useful when binding to port 0, and the server task.
+pub async fn serve(addr: SocketAddr) -> Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    let server = tokio::spawn(async move {
+        loop {
+            let Ok((conn, _)) = listener.accept().await else {
+                continue;
+            };
+            tokio::spawn(async move {
+                if let Err(err) = respond(conn).await {
+                    tracing::debug!("metrics request failed: {err:#}");
+                }
+            });
+        }
+    });
+    Ok((addr, server))
+}
+
+async fn respond(mut conn: TcpStream) -> Result<()> {
+    // Only the request line matters, which fits in the first read.
+    let mut buf = [0; 1024];
+    let len = conn.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let (status, body) = match request.lines().next() {
+        Some(line) if line.starts_with("GET /metrics ") => ("200 OK", METRICS.render()?),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    conn.write_all(response.as_bytes()).await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+/// Times an export call and the fuel it consumes, and brackets it with a
+/// guest profile when profiling per call.
+///
+/// [`CallTimer::finish`] has to be called whether or not the call succeeded,
+/// so a failed call still shows up in [`METRICS`] and its profile is written.
+#[must_use]
+pub(crate) struct CallTimer {
+    export: String,
+    start: Instant,
+    fuel: Option<u64>,
+}
+
+impl CallTimer {
+    pub(crate) fn start(store: &mut Store<Host>, export: &str) -> Self {
+        profiling::begin_call(store, export);
+        CallTimer {
+            export: export.to_owned(),
+            start: Instant::now(),
+            fuel: store.get_fuel().ok(),
+        }
+    }
+
+    pub(crate) fn finish(self, store: &mut Store<Host>) {
+        profiling::end_call(store);
+        let metrics = &*METRICS;
+        metrics
+            .calls
+            .with_label_values(&[&self.export])
+            .observe(self.start.elapsed().as_secs_f64());
+        if let (Some(before), Ok(after)) = (self.fuel, store.get_fuel()) {
+            metrics
+                .fuel
+                .with_label_values(&[&self.export])
+                .inc_by(before.saturating_sub(after));
+        }
+    }
+}
+
+/// Reports how far each store's linear memories grow, without limiting them.
+#[derive(Debug, Default)]
+pub(crate) struct HighWater;
+
+impl ResourceLimiter for HighWater {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        METRICS.memory_grown(desired);
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        _desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+}
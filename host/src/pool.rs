@@ -0,0 +1,209 @@
+//! A pool of worker threads, each owning its own [`Store`] and instance.
+//!
+//! A `Store<Host>` can only be used by one thread at a time, so the pool
+//! gives every worker a store of its own and a channel to receive calls on.
+//! [`Pool::call`] routes each call by an affinity key, such as a session id:
+//! calls with the same key always reach the same worker, and so see the
+//! state earlier calls left in its instance.
+//!
+//! The component is compiled once, off the runtime, and every worker
+//! instantiates that same compiled component. A worker whose instance traps
+//! instantiates it afresh for the calls after that, so the state the trapped
+//! instance held for its keys is lost, but the keys keep being served. A
+//! worker that fails to do so exits, and calls routed to it fail.
+//!
+//! [`Store`]: wasmtime::Store
+
+use std::hash::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tracing::Instrument;
+use wasmtime::component::Component;
+
+use crate::dynamic;
+use crate::dynamic::Arg;
+use crate::dynamic::Dynamic;
+use crate::dynamic::Ret;
+use crate::Options;
+
+/// A call waiting for a worker.
+struct Job {
+    export: String,
+    args: Vec<Arg>,
+    reply: oneshot::Sender<Result<Vec<Ret>>>,
+}
+
+struct Worker {
+    jobs: mpsc::UnboundedSender<Job>,
+    thread: JoinHandle<()>,
+}
+
+pub struct Pool {
+    workers: Vec<Worker>,
+}
+
+impl Pool {
+    /// Compiles the component once, then starts `workers` threads, each
+    /// instantiating it with what `options` returns, and waits until every
+    /// one of them is ready.
+    pub async fn new(
+        workers: usize,
+        options: impl Fn() -> Options + Send + Sync + 'static,
+    ) -> Result<Self> {
+        anyhow::ensure!(workers > 0, "a pool needs at least one worker");
+        let options: Arc<dyn Fn() -> Options + Send + Sync> = Arc::new(options);
+        let component = tokio::task::spawn_blocking({
+            let options = options.clone();
+            move || crate::compile(&options())
+        })
+        .await??;
+        let mut pool = Pool {
+            workers: Vec::with_capacity(workers),
+        };
+        let mut ready = Vec::with_capacity(workers);
+        for index in 0..workers {
+            let (jobs, receiver) = mpsc::unbounded_channel();
+            let (started, started_rx) = oneshot::channel();
+            let options = options.clone();
+            let component = component.clone();
+            let thread = std::thread::Builder::new()
+                .name(format!("host-worker-{index}"))
+                .spawn(move || work(index, &component, &*options, started, receiver))?;
+            pool.workers.push(Worker { jobs, thread });
+            ready.push(started_rx);
+        }
+        for (index, started) in ready.into_iter().enumerate() {
+            started
+                .await
+                .map_err(|_| anyhow!("worker {index} exited"))?
+                .with_context(|| format!("worker {index} failed to start"))?;
+        }
+        Ok(pool)
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// The worker calls with `affinity` are routed to.
+    pub fn worker_for(&self, affinity: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        affinity.hash(&mut hasher);
+        (hasher.finish() % self.workers.len() as u64) as usize
+    }
+
+    /// Calls `export` through [`Dynamic::call`] on the worker for `affinity`.
+    /// Calls to one worker run one at a time, in the order they were made.
+    pub async fn call(&self, affinity: &str, export: &str, args: Vec<Arg>) -> Result<Vec<Ret>> {
+        let index = self.worker_for(affinity);
+        let (reply, results) = oneshot::channel();
+        let job = Job {
+            export: export.to_owned(),
+            args,
+            reply,
+        };
+        self.workers[index]
+            .jobs
+            .send(job)
+            .map_err(|_| anyhow!("worker {index} has exited"))?;
+        results
+            .await
+            .map_err(|_| anyhow!("worker {index} dropped the call"))?
+    }
+}
+
+impl Pool {
+    /// Closes every worker's channel, and waits for the calls they have
+    /// already received and for their threads to exit.
+    pub async fn shutdown(mut self) -> Result<()> {
+        let threads: Vec<_> = self.workers.drain(..).map(|worker| worker.thread).collect();
+        tokio::task::spawn_blocking(move || {
+            for (index, thread) in threads.into_iter().enumerate() {
+                thread
+                    .join()
+                    .map_err(|_| anyhow!("worker {index} panicked"))?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+}
+
+impl Drop for Pool {
+    /// Closes every worker's channel without waiting: each thread finishes
+    /// the calls it has already received and then exits on its own. Use
+    /// [`Pool::shutdown`] to wait for that.
+    fn drop(&mut self) {
+        self.workers.clear();
+    }
+}
+
+/// A worker thread's main loop, on a runtime of its own.
+fn work(
+    index: usize,
+    component: &Component,
+    options: &(dyn Fn() -> Options + Send + Sync),
+    started: oneshot::Sender<Result<()>>,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+) {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            let _ = started.send(Err(err.into()));
+            return;
+        }
+    };
+    runtime.block_on(
+        async move {
+            let instantiated = crate::try_instantiate_compiled(component, options()).await;
+            let (instance, mut store) = match instantiated {
+                Ok(instantiated) => instantiated,
+                Err(err) => {
+                    let _ = started.send(Err(err));
+                    return;
+                }
+            };
+            let _ = started.send(Ok(()));
+            let mut dynamic = Dynamic::new(instance);
+            while let Some(job) = jobs.recv().await {
+                let results = dynamic.call(&mut store, &job.export, job.args).await;
+                let trapped = results.as_ref().is_err_and(dynamic::is_trap);
+                // The caller may have given up waiting.
+                let _ = job.reply.send(results);
+                if !trapped {
+                    continue;
+                }
+                tracing::warn!(
+                    export = job.export,
+                    "instance trapped, instantiating it again"
+                );
+                match crate::try_instantiate_compiled(component, options()).await {
+                    Ok((instance, fresh)) => {
+                        dynamic = Dynamic::new(instance);
+                        store = fresh;
+                    }
+                    Err(err) => {
+                        tracing::error!("worker exiting, failed to instantiate again: {err:#}");
+                        return;
+                    }
+                }
+            }
+        }
+        .instrument(tracing::info_span!("worker", index)),
+    );
+}
@@ -0,0 +1,290 @@
+//! Finding hot spots in the guest.
+//!
+//! [`Profiling::Guest`] samples the guest with wasmtime's [`GuestProfiler`]
+//! from an epoch deadline callback and writes profiles that open in
+//! <https://profiler.firefox.com>, either one per store or one per export
+//! call. [`Profiling::PerfMap`] and [`Profiling::JitDump`] instead let
+//! `perf` symbolize JIT code for the whole process.
+//!
+//! [`Options::profiling`] is off by default. The binaries set it from
+//! `HOST_PROFILE` with [`Profiling::from_env`]:
+//!
+//! | `HOST_PROFILE` | Mode                                          |
+//! |----------------|-----------------------------------------------|
+//! | unset, `off`   | [`Profiling::Off`]                            |
+//! | `guest`        | one profile per store, in `HOST_PROFILE_DIR`  |
+//! | `guest-call`   | one profile per call, in `HOST_PROFILE_DIR`   |
+//! | `perfmap`      | [`Profiling::PerfMap`]                        |
+//! | `jitdump`      | [`Profiling::JitDump`]                        |
+//!
+//! [`Options::profiling`]: crate::Options::profiling
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use wasmtime::component::Component;
+use wasmtime::CallHook;
+use wasmtime::Config;
+use wasmtime::Engine;
+use wasmtime::EngineWeak;
+use wasmtime::GuestProfiler;
+use wasmtime::ProfilingStrategy;
+use wasmtime::Store;
+use wasmtime::StoreContextMut;
+use wasmtime::UpdateDeadline;
+
+use crate::Host;
+
+pub const PROFILE_VAR: &str = "HOST_PROFILE";
+
+pub const PROFILE_DIR_VAR: &str = "HOST_PROFILE_DIR";
+
+/// How often the guest is sampled by default.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Profiling {
+    #[default]
+    Off,
+    /// Sample the guest every `interval` and write Firefox profiler JSON to
+    /// `dir`.
+    Guest {
+        interval: Duration,
+        scope: Scope,
+        dir: PathBuf,
+    },
+    /// Write `/tmp/perf-<pid>.map` for `perf report`.
+    PerfMap,
+    /// Write a jitdump file for `perf inject --jit`.
+    JitDump,
+}
+
+/// What a single guest profile covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Everything a store runs, written when the store is dropped.
+    Session,
+    /// A single call made through [`crate::call`] or
+    /// [`crate::dynamic::Dynamic::call`].
+    Call,
+}
+
+impl Profiling {
+    /// Reads [`PROFILE_VAR`] and [`PROFILE_DIR_VAR`].
+    pub fn from_env() -> Result<Self> {
+        let dir = || {
+            std::env::var_os(PROFILE_DIR_VAR)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| "profiles".into())
+        };
+        match std::env::var(PROFILE_VAR).as_deref() {
+            Err(_) | Ok("") | Ok("off") => Ok(Profiling::Off),
+            Ok("guest") => Ok(Profiling::Guest {
+                interval: DEFAULT_INTERVAL,
+                scope: Scope::Session,
+                dir: dir(),
+            }),
+            Ok("guest-call") => Ok(Profiling::Guest {
+                interval: DEFAULT_INTERVAL,
+                scope: Scope::Call,
+                dir: dir(),
+            }),
+            Ok("perfmap") => Ok(Profiling::PerfMap),
+            Ok("jitdump") => Ok(Profiling::JitDump),
+            Ok(other) => bail!(
+                "{PROFILE_VAR}={other}: expected `off`, `guest`, `guest-call`, `perfmap` or `jitdump`"
+            ),
+        }
+    }
+
+    pub(crate) fn configure(&self, config: &mut Config) {
+        match self {
+            Profiling::Off => {}
+            Profiling::Guest { .. } => {
+                config.epoch_interruption(true);
+            }
+            Profiling::PerfMap => {
+                config.profiler(ProfilingStrategy::PerfMap);
+            }
+            Profiling::JitDump => {
+                config.profiler(ProfilingStrategy::JitDump);
+            }
+        }
+    }
+
+    /// Starts sampling `store` if this is [`Profiling::Guest`].
+    pub(crate) fn install(&self, store: &mut Store<Host>, component: &Component) -> Result<()> {
+        let Profiling::Guest {
+            interval,
+            scope,
+            dir,
+        } = self
+        else {
+            return Ok(());
+        };
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let mut profiler = Profiler {
+            component: component.clone(),
+            interval: *interval,
+            scope: *scope,
+            dir: dir.clone(),
+            current: None,
+            last_sample: Instant::now(),
+        };
+        if *scope == Scope::Session {
+            profiler.start("session");
+        }
+        store.data_mut().profiler = Some(profiler);
+
+        start_ticker(store.engine(), *interval);
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(|mut store| {
+            with_profiler(&mut store, |profiler, store| profiler.sample(store));
+            Ok(UpdateDeadline::Continue(1))
+        });
+        Ok(())
+    }
+}
+
+/// A store's guest profiler, kept in [`Host`].
+pub(crate) struct Profiler {
+    component: Component,
+    interval: Duration,
+    scope: Scope,
+    dir: PathBuf,
+    current: Option<(String, GuestProfiler)>,
+    last_sample: Instant,
+}
+
+static NEXT_PROFILE: AtomicU64 = AtomicU64::new(0);
+
+impl Profiler {
+    /// Starts a new profile. One left over from a call that trapped is
+    /// written out first.
+    fn start(&mut self, name: &str) {
+        if let Err(err) = self.finish() {
+            tracing::warn!("failed to write guest profile: {err:#}");
+        }
+        let profiler =
+            GuestProfiler::new_component(name, self.interval, self.component.clone(), []);
+        self.current = Some((name.to_owned(), profiler));
+        self.last_sample = Instant::now();
+    }
+
+    fn sample(&mut self, store: &StoreContextMut<'_, Host>) {
+        if let Some((_, profiler)) = &mut self.current {
+            let now = Instant::now();
+            profiler.sample(store, now - self.last_sample);
+            self.last_sample = now;
+        }
+    }
+
+    fn call_hook(&mut self, store: &StoreContextMut<'_, Host>, hook: CallHook) {
+        if let Some((_, profiler)) = &mut self.current {
+            profiler.call_hook(store, hook);
+        }
+    }
+
+    /// Writes the current profile to `<dir>/<name>-<pid>-<n>.json`.
+    fn finish(&mut self) -> Result<()> {
+        let Some((name, profiler)) = self.current.take() else {
+            return Ok(());
+        };
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let n = NEXT_PROFILE.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .dir
+            .join(format!("{name}-{}-{n}.json", std::process::id()));
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+        profiler.finish(BufWriter::new(file))?;
+        tracing::info!(path = %path.display(), "wrote guest profile");
+        Ok(())
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            tracing::warn!("failed to write guest profile: {err:#}");
+        }
+    }
+}
+
+/// Starts a per-call profile for `export`, when profiling per call.
+pub(crate) fn begin_call(store: &mut Store<Host>, export: &str) {
+    if let Some(profiler) = &mut store.data_mut().profiler {
+        if profiler.scope == Scope::Call {
+            profiler.start(export);
+        }
+    }
+}
+
+/// Writes the profile started by [`begin_call`].
+pub(crate) fn end_call(store: &mut Store<Host>) {
+    if let Some(profiler) = &mut store.data_mut().profiler {
+        if profiler.scope == Scope::Call {
+            if let Err(err) = profiler.finish() {
+                tracing::warn!("failed to write guest profile: {err:#}");
+            }
+        }
+    }
+}
+
+/// Records a guest/host transition marker in the current profile.
+pub(crate) fn call_hook(store: &mut StoreContextMut<'_, Host>, hook: CallHook) {
+    with_profiler(store, |profiler, store| profiler.call_hook(store, hook));
+}
+
+/// The profiler needs the store to walk the guest's stack, so it is taken out
+/// of [`Host`] for the duration of `f`.
+fn with_profiler(
+    store: &mut StoreContextMut<'_, Host>,
+    f: impl FnOnce(&mut Profiler, &StoreContextMut<'_, Host>),
+) {
+    if let Some(mut profiler) = store.data_mut().profiler.take() {
+        f(&mut profiler, store);
+        store.data_mut().profiler = Some(profiler);
+    }
+}
+
+/// Engines whose epoch a ticker thread is bumping.
+static TICKING: Mutex<Vec<EngineWeak>> = Mutex::new(Vec::new());
+
+/// Bumps the engine's epoch every `interval` until the engine is dropped.
+/// Stores sharing an engine share its ticker, which keeps the interval of the
+/// first of them, so the epoch does not advance faster as stores are added.
+fn start_ticker(engine: &Engine, interval: Duration) {
+    let mut ticking = TICKING.lock().unwrap();
+    ticking.retain(|ticked| ticked.upgrade().is_some());
+    let running = ticking.iter().any(|ticked| {
+        ticked
+            .upgrade()
+            .is_some_and(|ticked| Engine::same(&ticked, engine))
+    });
+    if running {
+        return;
+    }
+    ticking.push(engine.weak());
+    let engine = engine.weak();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        match engine.upgrade() {
+            Some(engine) => engine.increment_epoch(),
+            None => break,
+        }
+    });
+}
@@ -0,0 +1,212 @@
+//! Swapping the guest component while the host keeps serving calls.
+//!
+//! A [`Reloader`] polls the component's path and, when the file changes,
+//! compiles and instantiates it in the background. Calls made after that go
+//! to the new [`Version`]; calls already running keep their `Arc` to the old
+//! one, which is dropped once the last of them returns. A component that
+//! fails to load is logged and skipped, and the current version stays.
+//!
+//! Compilation runs on tokio's blocking pool, so the runtime keeps serving
+//! the current version meanwhile; only instantiation happens on the runtime.
+//! A version whose instance traps instantiates its component again for the
+//! calls after that, losing the state the trapped instance held.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use anyhow::Result;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use wasmtime::component::Component;
+use wasmtime::component::Instance;
+use wasmtime::Store;
+
+use crate::deterministic::VirtualClock;
+use crate::dynamic;
+use crate::dynamic::Arg;
+use crate::dynamic::Dynamic;
+use crate::dynamic::Ret;
+use crate::ComponentSource;
+use crate::Host;
+use crate::Options;
+
+/// One load of the component.
+pub struct Version {
+    /// Counts up from 1 with every successful reload.
+    pub number: u64,
+    /// When the file was modified before it was loaded.
+    pub modified: SystemTime,
+    component: Component,
+    options: Arc<dyn Fn() -> Options + Send + Sync>,
+    instance: tokio::sync::Mutex<(Instance, Store<Host>)>,
+    clock: Mutex<Option<VirtualClock>>,
+}
+
+/// The results of a call, and the version that served it.
+#[derive(Debug)]
+pub struct Served {
+    pub version: u64,
+    pub results: Vec<Ret>,
+}
+
+impl Version {
+    /// The version's clock, when its options were
+    /// [deterministic](Options::deterministic). A trap replaces it along with
+    /// the instance.
+    pub fn clock(&self) -> Option<VirtualClock> {
+        self.clock.lock().unwrap().clone()
+    }
+
+    /// Calls `export` through [`Dynamic::call`].
+    ///
+    /// The version's store is locked until the call returns, so calls to the
+    /// same version run one at a time, in the order they took the lock, and a
+    /// slow call holds up the ones behind it. A [`Pool`] serves calls on
+    /// several stores in parallel.
+    ///
+    /// [`Pool`]: crate::pool::Pool
+    pub async fn call(&self, export: &str, args: Vec<Arg>) -> Result<Served> {
+        let mut instance = self.instance.lock().await;
+        let (current, store) = &mut *instance;
+        let results = Dynamic::new(*current)
+            .call(store, export, args)
+            .instrument(tracing::info_span!("version", number = self.number))
+            .await;
+        let results = match results {
+            Err(err) if dynamic::is_trap(&err) => {
+                tracing::warn!(
+                    version = self.number,
+                    export,
+                    "instance trapped, instantiating it again"
+                );
+                let fresh = crate::try_instantiate_compiled(&self.component, (self.options)())
+                    .await
+                    .context("failed to instantiate again after a trap")?;
+                *self.clock.lock().unwrap() = fresh.1.data().clock().cloned();
+                *instance = fresh;
+                return Err(err);
+            }
+            results => results?,
+        };
+        tracing::debug!(version = self.number, export, "served call");
+        Ok(Served {
+            version: self.number,
+            results,
+        })
+    }
+}
+
+pub struct Reloader {
+    current: Arc<Mutex<Arc<Version>>>,
+    watcher: JoinHandle<()>,
+}
+
+impl Reloader {
+    /// Loads the component at `path` and checks it for changes every
+    /// `interval`. Each version is instantiated with what `options` returns,
+    /// with [`Options::component`] set to `path`.
+    pub async fn new(
+        path: impl Into<PathBuf>,
+        interval: Duration,
+        options: impl Fn() -> Options + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let path = path.into();
+        let options: Arc<dyn Fn() -> Options + Send + Sync> = Arc::new(options);
+        let modified = modified(&path)?;
+        let version = load(&path, 1, modified, &options).await?;
+        let current = Arc::new(Mutex::new(Arc::new(version)));
+        let watcher = tokio::spawn(watch(path, interval, options, current.clone()));
+        Ok(Self { current, watcher })
+    }
+
+    /// The version new calls go to.
+    pub fn current(&self) -> Arc<Version> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Calls `export` on the current version.
+    pub async fn call(&self, export: &str, args: Vec<Arg>) -> Result<Served> {
+        self.current().call(export, args).await
+    }
+}
+
+impl Drop for Reloader {
+    fn drop(&mut self) {
+        self.watcher.abort();
+    }
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    let metadata =
+        std::fs::metadata(path).with_context(|| format!("failed to stat {}", path.display()))?;
+    Ok(metadata.modified()?)
+}
+
+async fn load(
+    path: &Path,
+    number: u64,
+    modified: SystemTime,
+    options: &Arc<dyn Fn() -> Options + Send + Sync>,
+) -> Result<Version> {
+    let factory = options.clone();
+    let options = Options {
+        component: ComponentSource::Path(path.to_owned()),
+        ..options()
+    };
+    // Compiling takes long enough to stall every other task on a
+    // current-thread runtime, calls to the current version included.
+    let (component, options) = tokio::task::spawn_blocking(move || {
+        let component = crate::compile(&options)?;
+        anyhow::Ok((component, options))
+    })
+    .await?
+    .with_context(|| format!("failed to compile {}", path.display()))?;
+    let (instance, store) = crate::try_instantiate_compiled(&component, options)
+        .await
+        .with_context(|| format!("failed to instantiate {}", path.display()))?;
+    Ok(Version {
+        number,
+        modified,
+        clock: Mutex::new(store.data().clock().cloned()),
+        component,
+        options: factory,
+        instance: tokio::sync::Mutex::new((instance, store)),
+    })
+}
+
+async fn watch(
+    path: PathBuf,
+    interval: Duration,
+    options: Arc<dyn Fn() -> Options + Send + Sync>,
+    current: Arc<Mutex<Arc<Version>>>,
+) {
+    let (mut number, mut seen) = {
+        let current = current.lock().unwrap();
+        (current.number, current.modified)
+    };
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        // The file may be missing for a moment while it is being replaced.
+        let Ok(modified) = modified(&path) else {
+            continue;
+        };
+        if modified == seen {
+            continue;
+        }
+        seen = modified;
+        match load(&path, number + 1, modified, &options).await {
+            Ok(version) => {
+                number += 1;
+                tracing::info!(version = number, path = %path.display(), "reloaded component");
+                *current.lock().unwrap() = Arc::new(version);
+            }
+            Err(err) => tracing::warn!("keeping version {number}: {err:#}"),
+        }
+    }
+}
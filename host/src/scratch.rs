@@ -0,0 +1,636 @@
+//! Per-instance writable scratch directories with usage quotas.
+//!
+//! Quotas are enforced where the guest changes what the directory holds:
+//! [`add_to_linker`] replaces `open-at` and `create-directory-at`, which
+//! charge every new entry to the file count, and `write-via-stream`,
+//! `append-via-stream` and `set-size`, which charge every byte a file grows
+//! by. An operation that would cross a limit fails with `error-code::quota`
+//! before it touches the directory. The instance itself keeps running.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+use anyhow::Result;
+use cap_fs_ext::FollowSymlinks;
+use cap_fs_ext::OpenOptionsFollowExt;
+use cap_fs_ext::OpenOptionsMaybeDirExt;
+use tempfile::TempDir;
+use wasmtime::component::Accessor;
+use wasmtime::component::HostStream;
+use wasmtime::component::Linker;
+use wasmtime::component::Resource;
+use wasmtime::component::StreamReader;
+use wasmtime_wasi::p3::bindings::filesystem::types::DescriptorFlags;
+use wasmtime_wasi::p3::bindings::filesystem::types::ErrorCode;
+use wasmtime_wasi::p3::bindings::filesystem::types::OpenFlags;
+use wasmtime_wasi::p3::bindings::filesystem::types::PathFlags;
+use wasmtime_wasi::p3::filesystem::Descriptor;
+use wasmtime_wasi::p3::filesystem::Dir;
+use wasmtime_wasi::p3::filesystem::DirPerms;
+use wasmtime_wasi::p3::filesystem::File;
+use wasmtime_wasi::p3::filesystem::FilePerms;
+use wasmtime_wasi::p3::filesystem::OpenMode;
+
+use crate::telemetry;
+use crate::Host;
+
+/// Limits on what a guest may keep in its scratch directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// Total size of all regular files, in bytes.
+    pub max_bytes: u64,
+    /// Number of files and directories.
+    pub max_files: u64,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024 * 1024,
+            max_files: 1024,
+        }
+    }
+}
+
+/// What a scratch directory currently holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// Error raised when a guest has written past its [`Quota`].
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub usage: Usage,
+    pub quota: Quota,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "scratch quota exceeded: {} bytes in {} files (limit {} bytes in {} files)",
+            self.usage.bytes, self.usage.files, self.quota.max_bytes, self.quota.max_files
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// A temporary directory mounted as the guest's `scratch` preopen.
+///
+/// The directory and everything in it is removed when the `Scratch` is
+/// dropped, which happens together with the owning `Store`.
+pub struct Scratch {
+    dir: TempDir,
+    meter: Meter,
+}
+
+impl Scratch {
+    pub fn new(quota: Quota) -> Result<Self> {
+        let dir = TempDir::new()?;
+        let meter = Meter {
+            root: dir.path().to_path_buf(),
+            quota,
+            ledger: Arc::default(),
+        };
+        Ok(Self { dir, meter })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn quota(&self) -> Quota {
+        self.meter.quota
+    }
+
+    /// Walks the directory and adds up what it holds.
+    pub fn usage(&self) -> Result<Usage> {
+        usage(self.dir.path())
+    }
+
+    fn meter(&self) -> Meter {
+        self.meter.clone()
+    }
+}
+
+fn usage(root: &Path) -> Result<Usage> {
+    let mut usage = Usage::default();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            usage.files += 1;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                usage.bytes += metadata.len();
+            }
+        }
+    }
+    Ok(usage)
+}
+
+/// Bytes in use by a scratch directory, as far as its writes know.
+#[derive(Default)]
+struct Ledger {
+    bytes: u64,
+    files: u64,
+    /// Writes currently in progress.
+    writers: usize,
+}
+
+/// Charges writes and new entries to a scratch directory's [`Quota`].
+/// Clones share one ledger.
+#[derive(Clone)]
+struct Meter {
+    root: PathBuf,
+    quota: Quota,
+    ledger: Arc<Mutex<Ledger>>,
+}
+
+impl Meter {
+    /// Locks the ledger. When no write is in progress, it is first settled
+    /// against what the directory actually holds, which credits back space
+    /// freed by truncating or removing files.
+    fn ledger(&self) -> Result<MutexGuard<'_, Ledger>> {
+        let mut ledger = self.ledger.lock().unwrap();
+        if ledger.writers == 0 {
+            let usage = usage(&self.root)?;
+            ledger.bytes = usage.bytes;
+            ledger.files = usage.files;
+        }
+        Ok(ledger)
+    }
+
+    /// Starts metering a write.
+    fn begin(&self) -> Result<Metered> {
+        let mut ledger = self.ledger()?;
+        if ledger.files > self.quota.max_files {
+            return Err(QuotaExceeded {
+                usage: Usage {
+                    bytes: ledger.bytes,
+                    files: ledger.files,
+                },
+                quota: self.quota,
+            }
+            .into());
+        }
+        ledger.writers += 1;
+        Ok(Metered {
+            meter: self.clone(),
+        })
+    }
+
+    /// Runs `create`, which adds one file or directory, unless that would
+    /// go past the file count. The ledger stays locked meanwhile, so
+    /// concurrent creations cannot both take the last slot.
+    fn create<R>(&self, create: impl FnOnce() -> io::Result<R>) -> Result<R> {
+        let mut ledger = self.ledger()?;
+        let files = ledger.files + 1;
+        if files > self.quota.max_files {
+            return Err(QuotaExceeded {
+                usage: Usage {
+                    bytes: ledger.bytes,
+                    files,
+                },
+                quota: self.quota,
+            }
+            .into());
+        }
+        let created = create()?;
+        ledger.files = files;
+        Ok(created)
+    }
+}
+
+/// One write in progress.
+struct Metered {
+    meter: Meter,
+}
+
+impl Metered {
+    /// Charges growing a file of `len` bytes to `end` bytes, or fails without
+    /// charging anything if that would go past the quota.
+    fn charge(&self, len: u64, end: u64) -> Result<(), QuotaExceeded> {
+        let growth = end.saturating_sub(len);
+        let mut ledger = self.meter.ledger.lock().unwrap();
+        let bytes = ledger.bytes + growth;
+        if bytes > self.meter.quota.max_bytes {
+            return Err(QuotaExceeded {
+                usage: Usage {
+                    bytes,
+                    files: ledger.files,
+                },
+                quota: self.meter.quota,
+            });
+        }
+        ledger.bytes = bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Metered {
+    fn drop(&mut self) {
+        self.meter.ledger.lock().unwrap().writers -= 1;
+    }
+}
+
+/// Where a metered write puts each chunk.
+#[derive(Clone, Copy)]
+enum At {
+    Offset(u64),
+    End,
+}
+
+/// Replaces the methods of `wasi:filesystem/types`' `descriptor` that add
+/// entries or grow files with versions that enforce the instance's
+/// [`Quota`].
+///
+/// Must be called after the regular filesystem has been added to `linker`,
+/// and only for instances with a scratch directory: writable descriptors only
+/// ever point into it.
+pub fn add_to_linker(linker: &mut Linker<Host>) -> Result<()> {
+    linker.allow_shadowing(true);
+    let mut types = linker.instance("wasi:filesystem/types@0.3.0")?;
+    types.func_wrap_concurrent(
+        "[method]descriptor.open-at",
+        |accessor: &mut Accessor<Host>,
+         (fd, path_flags, path, open_flags, flags): (
+            Resource<Descriptor>,
+            PathFlags,
+            String,
+            OpenFlags,
+            DescriptorFlags,
+        )| {
+            telemetry::import(
+                accessor,
+                "wasi:filesystem/types@0.3.0#[method]descriptor.open-at",
+            );
+            let dir = directory(accessor, &fd);
+            Box::pin(async move {
+                let opened = match dir? {
+                    Ok((dir, meter)) => {
+                        open_at(dir, meter, path_flags, path, open_flags, flags).await
+                    }
+                    Err(code) => Err(code),
+                };
+                let fd = match opened {
+                    Ok(descriptor) => {
+                        Ok(accessor.with(|mut view| view.get().table.push(descriptor))?)
+                    }
+                    Err(code) => Err(code),
+                };
+                Ok((fd,))
+            })
+        },
+    )?;
+    types.func_wrap_concurrent(
+        "[method]descriptor.create-directory-at",
+        |accessor: &mut Accessor<Host>, (fd, path): (Resource<Descriptor>, String)| {
+            telemetry::import(
+                accessor,
+                "wasi:filesystem/types@0.3.0#[method]descriptor.create-directory-at",
+            );
+            let dir = directory(accessor, &fd);
+            Box::pin(async move {
+                let created = match dir? {
+                    Ok((dir, meter)) => create_directory_at(dir, meter, path).await,
+                    Err(code) => Err(code),
+                };
+                Ok((created,))
+            })
+        },
+    )?;
+    types.func_wrap_concurrent(
+        "[method]descriptor.set-size",
+        |accessor: &mut Accessor<Host>, (fd, size): (Resource<Descriptor>, u64)| {
+            telemetry::import(
+                accessor,
+                "wasi:filesystem/types@0.3.0#[method]descriptor.set-size",
+            );
+            let file = writable(accessor, &fd);
+            Box::pin(async move {
+                let (file, meter) = file?;
+                Ok((set_size(file, meter, size).await,))
+            })
+        },
+    )?;
+    types.func_wrap_concurrent(
+        "[method]descriptor.write-via-stream",
+        |accessor: &mut Accessor<Host>,
+         (fd, data, offset): (Resource<Descriptor>, HostStream<u8>, u64)| {
+            telemetry::import(
+                accessor,
+                "wasi:filesystem/types@0.3.0#[method]descriptor.write-via-stream",
+            );
+            let write = begin(accessor, &fd, data);
+            Box::pin(async move { Ok((write?.run(At::Offset(offset)).await,)) })
+        },
+    )?;
+    types.func_wrap_concurrent(
+        "[method]descriptor.append-via-stream",
+        |accessor: &mut Accessor<Host>, (fd, data): (Resource<Descriptor>, HostStream<u8>)| {
+            telemetry::import(
+                accessor,
+                "wasi:filesystem/types@0.3.0#[method]descriptor.append-via-stream",
+            );
+            let write = begin(accessor, &fd, data);
+            Box::pin(async move { Ok((write?.run(At::End).await,)) })
+        },
+    )?;
+    linker.allow_shadowing(false);
+    Ok(())
+}
+
+fn meter(host: &Host) -> Result<Meter> {
+    host.scratch
+        .as_ref()
+        .map(Scratch::meter)
+        .ok_or_else(|| anyhow::anyhow!("metered filesystems need a scratch directory"))
+}
+
+/// The directory `fd` refers to, and the meter to charge new entries to.
+fn directory(
+    accessor: &mut Accessor<Host>,
+    fd: &Resource<Descriptor>,
+) -> Result<Result<(Dir, Meter), ErrorCode>> {
+    accessor.with(|mut view| {
+        let host = view.get();
+        let meter = meter(host)?;
+        Ok(host.table.get(fd)?.dir().map(|dir| (dir.clone(), meter)))
+    })
+}
+
+/// A handle of its own on the file `fd` refers to, if it may be written, and
+/// the meter to charge its growth to.
+fn writable(
+    accessor: &mut Accessor<Host>,
+    fd: &Resource<Descriptor>,
+) -> Result<(Result<std::fs::File, ErrorCode>, Meter)> {
+    accessor.with(|mut view| {
+        let host = view.get();
+        let meter = meter(host)?;
+        let file = match host.table.get(fd)?.file() {
+            Ok(file) if file.perms.contains(FilePerms::WRITE) => file
+                .file
+                .try_clone()
+                .map(|file| file.into_std())
+                .map_err(error_code),
+            Ok(_) => Err(ErrorCode::BadDescriptor),
+            Err(code) => Err(code),
+        };
+        Ok((file, meter))
+    })
+}
+
+/// wasmtime-wasi's `open-at`, except that an entry `CREATE` adds is charged
+/// to the file count first.
+async fn open_at(
+    dir: Dir,
+    meter: Meter,
+    path_flags: PathFlags,
+    path: String,
+    open_flags: OpenFlags,
+    flags: DescriptorFlags,
+) -> Result<Descriptor, ErrorCode> {
+    let create = open_flags.contains(OpenFlags::CREATE);
+    let truncate = open_flags.contains(OpenFlags::TRUNCATE);
+    if !dir.perms.contains(DirPerms::READ) {
+        return Err(ErrorCode::NotPermitted);
+    }
+    if !dir.perms.contains(DirPerms::MUTATE)
+        && (create || truncate || flags.contains(DescriptorFlags::WRITE))
+    {
+        return Err(ErrorCode::NotPermitted);
+    }
+    if open_flags.contains(OpenFlags::DIRECTORY)
+        && (create || truncate || open_flags.contains(OpenFlags::EXCLUSIVE))
+    {
+        return Err(ErrorCode::Invalid);
+    }
+    if flags.intersects(
+        DescriptorFlags::FILE_INTEGRITY_SYNC
+            | DescriptorFlags::DATA_INTEGRITY_SYNC
+            | DescriptorFlags::REQUESTED_WRITE_SYNC,
+    ) {
+        return Err(ErrorCode::Unsupported);
+    }
+
+    let mut options = cap_std::fs::OpenOptions::new();
+    let mut mode = OpenMode::empty();
+    options.maybe_dir(true);
+    if create {
+        if open_flags.contains(OpenFlags::EXCLUSIVE) {
+            options.create_new(true);
+        } else {
+            options.create(true);
+        }
+        options.write(true);
+        mode |= OpenMode::WRITE;
+    }
+    if truncate {
+        options.truncate(true).write(true);
+    }
+    if flags.contains(DescriptorFlags::WRITE) {
+        options.write(true);
+        mode |= OpenMode::WRITE;
+    }
+    // Open for reading unless asked to write only, like wasmtime-wasi does,
+    // and leave it to the permissions to reject reads later.
+    if flags.contains(DescriptorFlags::READ) || !flags.contains(DescriptorFlags::WRITE) {
+        options.read(true);
+        mode |= OpenMode::READ;
+    }
+    options.follow(if path_flags.contains(PathFlags::SYMLINK_FOLLOW) {
+        FollowSymlinks::Yes
+    } else {
+        FollowSymlinks::No
+    });
+    if mode.contains(OpenMode::WRITE) && !dir.file_perms.contains(FilePerms::WRITE) {
+        return Err(ErrorCode::NotPermitted);
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let root = &dir.dir;
+        let open = || root.open_with(&path, &options);
+        let file = if create && root.symlink_metadata(&path).is_err() {
+            meter.create(open).map_err(error)?
+        } else {
+            open().map_err(error_code)?
+        };
+        if file.metadata().map_err(error_code)?.is_dir() {
+            let opened = cap_std::fs::Dir::from_std_file(file.into_std());
+            // The host never lets WASI block the runtime's own threads.
+            Ok(Descriptor::Dir(Dir::new(
+                opened,
+                dir.perms,
+                dir.file_perms,
+                mode,
+                false,
+            )))
+        } else if open_flags.contains(OpenFlags::DIRECTORY) {
+            Err(ErrorCode::NotDirectory)
+        } else {
+            Ok(Descriptor::File(File::new(
+                file,
+                dir.file_perms,
+                mode,
+                false,
+            )))
+        }
+    })
+    .await
+    .map_err(|_| ErrorCode::Io)?
+}
+
+async fn create_directory_at(dir: Dir, meter: Meter, path: String) -> Result<(), ErrorCode> {
+    if !dir.perms.contains(DirPerms::MUTATE) {
+        return Err(ErrorCode::NotPermitted);
+    }
+    tokio::task::spawn_blocking(move || meter.create(|| dir.dir.create_dir(&path)))
+        .await
+        .map_err(|_| ErrorCode::Io)?
+        .map_err(error)
+}
+
+/// Truncates or extends the file to `size` bytes, charging any growth.
+async fn set_size(
+    file: Result<std::fs::File, ErrorCode>,
+    meter: Meter,
+    size: u64,
+) -> Result<(), ErrorCode> {
+    let file = file?;
+    tokio::task::spawn_blocking(move || {
+        let metered = meter.begin()?;
+        metered.charge(file.metadata()?.len(), size)?;
+        file.set_len(size)?;
+        anyhow::Ok(())
+    })
+    .await
+    .map_err(|_| ErrorCode::Io)?
+    .map_err(error)
+}
+
+/// A write-via-stream call that has resolved its descriptor.
+struct Write {
+    file: Result<std::fs::File, ErrorCode>,
+    data: StreamReader<Vec<u8>>,
+    meter: Meter,
+}
+
+fn begin(
+    accessor: &mut Accessor<Host>,
+    fd: &Resource<Descriptor>,
+    data: HostStream<u8>,
+) -> Result<Write> {
+    let (file, meter) = writable(accessor, fd)?;
+    let data = accessor.with(|mut view| data.into_reader(&mut view));
+    Ok(Write { file, data, meter })
+}
+
+impl Write {
+    /// Copies the stream into the file a chunk at a time, charging each chunk
+    /// before it is written.
+    async fn run(self, at: At) -> Result<(), ErrorCode> {
+        let file = Arc::new(self.file?);
+        let meter = self.meter;
+        let metered = tokio::task::spawn_blocking(move || meter.begin())
+            .await
+            .map_err(|_| ErrorCode::Io)?
+            .map_err(error)?;
+        let metered = Arc::new(metered);
+        let mut data = self.data;
+        let mut offset = match at {
+            At::Offset(offset) => offset,
+            At::End => 0,
+        };
+        loop {
+            let (next, chunk) = data.read(Vec::with_capacity(64 * 1024)).into_future().await;
+            if !chunk.is_empty() {
+                let file = file.clone();
+                let metered = metered.clone();
+                offset = tokio::task::spawn_blocking(move || {
+                    let len = file.metadata().map_err(error_code)?.len();
+                    let start = match at {
+                        At::Offset(_) => offset,
+                        At::End => len,
+                    };
+                    let end = start + chunk.len() as u64;
+                    metered.charge(len, end).map_err(|err| error(err.into()))?;
+                    write_all_at(&file, &chunk, start).map_err(error_code)?;
+                    Ok(end)
+                })
+                .await
+                .map_err(|_| ErrorCode::Io)??;
+            }
+            match next {
+                Some(next) => data = next,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Writes all of `buf` at `offset` without moving the file's cursor, which
+/// other handles on the same file share.
+#[cfg(unix)]
+fn write_all_at(file: &std::fs::File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &std::fs::File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn error(err: anyhow::Error) -> ErrorCode {
+    if let Some(exceeded) = err.downcast_ref::<QuotaExceeded>() {
+        tracing::warn!("{exceeded}");
+        return ErrorCode::Quota;
+    }
+    match err.downcast::<io::Error>() {
+        Ok(err) => error_code(err),
+        Err(err) => {
+            tracing::warn!("failed to meter scratch directory: {err:#}");
+            ErrorCode::Io
+        }
+    }
+}
+
+fn error_code(err: io::Error) -> ErrorCode {
+    use std::io::ErrorKind;
+    match err.kind() {
+        ErrorKind::NotFound => ErrorCode::NoEntry,
+        ErrorKind::AlreadyExists => ErrorCode::Exist,
+        ErrorKind::PermissionDenied => ErrorCode::NotPermitted,
+        ErrorKind::InvalidInput => ErrorCode::Invalid,
+        ErrorKind::NotADirectory => ErrorCode::NotDirectory,
+        ErrorKind::IsADirectory => ErrorCode::IsDirectory,
+        ErrorKind::DirectoryNotEmpty => ErrorCode::NotEmpty,
+        ErrorKind::StorageFull => ErrorCode::InsufficientSpace,
+        ErrorKind::FileTooLarge => ErrorCode::FileTooLarge,
+        ErrorKind::Interrupted => ErrorCode::Interrupted,
+        _ => ErrorCode::Io,
+    }
+}
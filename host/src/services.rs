@@ -0,0 +1,301 @@
+//! `pkg:component/host-services`, the functions the host lends the guest: a
+//! key-value store, logging, counters and a model backend for
+//! `session.infer`.
+//!
+//! `ticks` returns a stream written by a background task, which keeps ticking
+//! while the guest is busy elsewhere and stops once the guest drops the
+//! stream.
+//!
+//! A [`Services`] is handed to each store through [`Options::services`].
+//! Clones share their state, so passing the same one to several stores lets
+//! them see each other's keys and counters.
+//!
+//! [`Options::services`]: crate::Options::services
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use tokio::sync::watch;
+use wasmtime::component::Accessor;
+use wasmtime::component::ComponentType;
+use wasmtime::component::HostStream;
+use wasmtime::component::Lift;
+use wasmtime::component::Linker;
+use wasmtime::component::Lower;
+use wasmtime::component::StreamWriter;
+use wasmtime::component::VecBuffer;
+use wasmtime_wasi::p3::AccessorTaskFn;
+
+use crate::deterministic::VirtualClock;
+use crate::metrics::Live;
+use crate::metrics::METRICS;
+use crate::telemetry;
+use crate::Host;
+
+/// Answers the guest's `infer` calls.
+pub trait Model: Send + Sync {
+    fn infer(&self, prompt: String) -> Pin<Box<dyn Future<Output = Result<String>> + Send>>;
+}
+
+/// A [`Model`] which answers every prompt with `echo: <prompt>`.
+#[derive(Debug, Default)]
+pub struct Echo;
+
+impl Model for Echo {
+    fn infer(&self, prompt: String) -> Pin<Box<dyn Future<Output = Result<String>> + Send>> {
+        Box::pin(async move { Ok(format!("echo: {prompt}")) })
+    }
+}
+
+#[derive(Clone)]
+pub struct Services {
+    kv: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    counters: Arc<Mutex<HashMap<String, u64>>>,
+    model: Arc<dyn Model>,
+    /// How many `ticks` streams are being written.
+    tickers: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Services {
+    fn default() -> Self {
+        Services::new(Arc::new(Echo))
+    }
+}
+
+impl Services {
+    pub fn new(model: Arc<dyn Model>) -> Self {
+        Self {
+            kv: Arc::default(),
+            counters: Arc::default(),
+            model,
+            tickers: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.kv.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn put(&self, key: String, value: Vec<u8>) {
+        self.kv.lock().unwrap().insert(key, value);
+    }
+
+    pub fn counter(&self, name: &str) -> u64 {
+        self.counters
+            .lock()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Adds `by` to the counter `name`, which is also reported as
+    /// `host_guest_counter_total`.
+    pub fn increment(&self, name: &str, by: u64) -> u64 {
+        METRICS.guest_counters.with_label_values(&[name]).inc_by(by);
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(name.to_owned()).or_default();
+        *counter += by;
+        *counter
+    }
+
+    /// How many `ticks` streams are still being written.
+    pub fn tickers(&self) -> usize {
+        *self.tickers.borrow()
+    }
+
+    /// Completes once exactly `n` `ticks` streams are being written.
+    pub async fn tickers_reach(&self, n: usize) {
+        // The sender is kept alive by `self`, so this cannot fail.
+        let _ = self
+            .tickers
+            .subscribe()
+            .wait_for(|&tickers| tickers == n)
+            .await;
+    }
+}
+
+/// Host-side mirror of `pkg:component/host-services#level`.
+#[derive(ComponentType, Lift, Lower, Debug, Clone, Copy, PartialEq, Eq)]
+#[component(enum)]
+#[repr(u8)]
+pub enum Level {
+    #[component(name = "trace")]
+    Trace,
+    #[component(name = "debug")]
+    Debug,
+    #[component(name = "info")]
+    Info,
+    #[component(name = "warn")]
+    Warn,
+    #[component(name = "error")]
+    Error,
+}
+
+/// Host-side mirror of `pkg:component/host-services#tick`.
+#[derive(ComponentType, Lift, Lower, Debug, Clone, Copy, PartialEq, Eq)]
+#[component(record)]
+pub struct Tick {
+    pub seq: u64,
+    pub elapsed: u64,
+}
+
+/// Emits `message` as an event with the `guest` target.
+fn log(level: Level, message: &str) {
+    match level {
+        Level::Trace => tracing::trace!(target: "guest", "{message}"),
+        Level::Debug => tracing::debug!(target: "guest", "{message}"),
+        Level::Info => tracing::info!(target: "guest", "{message}"),
+        Level::Warn => tracing::warn!(target: "guest", "{message}"),
+        Level::Error => tracing::error!(target: "guest", "{message}"),
+    }
+}
+
+pub fn add_to_linker(linker: &mut Linker<Host>) -> Result<()> {
+    let mut services = linker.instance("pkg:component/host-services")?;
+    services.func_wrap_concurrent("get", |accessor: &mut Accessor<Host>, (key,): (String,)| {
+        telemetry::import(accessor, "pkg:component/host-services#get");
+        let services = accessor.with(|mut view| view.get().services.clone());
+        Box::pin(async move { Ok((services.get(&key),)) })
+    })?;
+    services.func_wrap_concurrent(
+        "put",
+        |accessor: &mut Accessor<Host>, (key, value): (String, Vec<u8>)| {
+            telemetry::import(accessor, "pkg:component/host-services#put");
+            let services = accessor.with(|mut view| view.get().services.clone());
+            Box::pin(async move {
+                services.put(key, value);
+                Ok(())
+            })
+        },
+    )?;
+    services.func_wrap_concurrent(
+        "log",
+        |accessor: &mut Accessor<Host>, (level, message): (Level, String)| {
+            telemetry::import(accessor, "pkg:component/host-services#log");
+            Box::pin(async move {
+                log(level, &message);
+                Ok(())
+            })
+        },
+    )?;
+    services.func_wrap_concurrent(
+        "increment",
+        |accessor: &mut Accessor<Host>, (name, by): (String, u64)| {
+            telemetry::import(accessor, "pkg:component/host-services#increment");
+            let services = accessor.with(|mut view| view.get().services.clone());
+            Box::pin(async move { Ok((services.increment(&name, by),)) })
+        },
+    )?;
+    services.func_wrap_concurrent(
+        "infer",
+        |accessor: &mut Accessor<Host>, (prompt,): (String,)| {
+            telemetry::import(accessor, "pkg:component/host-services#infer");
+            let services = accessor.with(|mut view| view.get().services.clone());
+            Box::pin(async move {
+                let response = services
+                    .model
+                    .infer(prompt)
+                    .await
+                    .map_err(|err| format!("{err:#}"));
+                Ok((response,))
+            })
+        },
+    )?;
+    services.func_wrap_concurrent(
+        "ticks",
+        |accessor: &mut Accessor<Host>, (interval,): (u64,)| {
+            telemetry::import(accessor, "pkg:component/host-services#ticks");
+            let ticks = accessor.with(|mut view| {
+                let instance = view.instance();
+                let (tx, rx) =
+                    instance.stream::<Tick, VecBuffer<Tick>, Vec<Tick>, _, _>(&mut view)?;
+                let host = view.get();
+                let ticker = Ticker::new(host.clock.clone(), &host.services);
+                let interval = Duration::from_nanos(interval);
+                view.spawn(AccessorTaskFn(move |_: &mut Accessor<Host>| {
+                    ticker.run(tx, interval)
+                }));
+                anyhow::Ok(HostStream::from(rx))
+            });
+            Box::pin(async move { Ok((ticks?,)) })
+        },
+    )?;
+    Ok(())
+}
+
+/// The background task behind a `ticks` stream.
+struct Ticker {
+    clock: Option<VirtualClock>,
+    start: Instant,
+    tickers: Arc<watch::Sender<usize>>,
+}
+
+impl Ticker {
+    fn new(clock: Option<VirtualClock>, services: &Services) -> Self {
+        services.tickers.send_modify(|tickers| *tickers += 1);
+        Ticker {
+            clock,
+            start: Instant::now(),
+            tickers: services.tickers.clone(),
+        }
+    }
+
+    /// Writes a [`Tick`] to `tx` every `interval` until the reader drops the
+    /// stream. A reader that goes away mid-interval ends the task right away,
+    /// rather than at the next write.
+    async fn run(self, tx: StreamWriter<VecBuffer<Tick>>, interval: Duration) -> Result<()> {
+        let _live = Live::stream();
+        let origin = self.clock.as_ref().map(VirtualClock::monotonic);
+        let (closed, mut watch) = tx.watch_reader();
+        let mut closed = Box::pin(closed);
+        for seq in 0.. {
+            let sleep = async {
+                match (&self.clock, origin) {
+                    (Some(clock), Some(origin)) => {
+                        clock.sleep(interval).await;
+                        clock.monotonic() - origin
+                    }
+                    _ => {
+                        tokio::time::sleep(interval).await;
+                        self.start.elapsed().as_nanos() as u64
+                    }
+                }
+            };
+            let elapsed = tokio::select! {
+                elapsed = sleep => elapsed,
+                () = &mut closed => {
+                    tracing::debug!(seq, "guest dropped the ticks stream");
+                    break;
+                }
+            };
+            let tick = Tick { seq, elapsed };
+            let tx = watch.into_inner();
+            match tx.write(VecBuffer::from(vec![tick])).into_future().await {
+                (Some(next), _) => {
+                    METRICS.stream_items.with_label_values(&["write"]).inc();
+                    let (next_closed, next_watch) = next.watch_reader();
+                    closed = Box::pin(next_closed);
+                    watch = next_watch;
+                }
+                (None, _) => {
+                    tracing::debug!(seq, "guest dropped the ticks stream");
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        self.tickers.send_modify(|tickers| *tickers -= 1);
+    }
+}
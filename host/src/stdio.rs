@@ -0,0 +1,303 @@
+//! Where a guest's standard input, output and error go.
+//!
+//! The guest reaches stdio both through preview 2 (its standard library, via
+//! the preview 1 adapter) and through the preview 3 `wasi:cli` streams, so each
+//! setting here is applied to both contexts and shares one underlying buffer
+//! or reader.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use wasmtime_wasi::p3::cli::InputStream;
+use wasmtime_wasi::p3::cli::IsTerminal;
+use wasmtime_wasi::p3::cli::OutputStream;
+use wasmtime_wasi::p3::cli::WasiCliCtx;
+use wasmtime_wasi::pipe::AsyncReadStream;
+use wasmtime_wasi::pipe::AsyncWriteStream;
+use wasmtime_wasi::AsyncStdinStream;
+use wasmtime_wasi::AsyncStdoutStream;
+use wasmtime_wasi::WasiCtxBuilder;
+
+/// How much output the preview 2 stream buffers before applying backpressure.
+const WRITE_BUDGET: usize = 64 * 1024;
+
+/// Destination for the guest's stdout or stderr.
+#[derive(Default)]
+pub enum Output {
+    /// Write to the host process's own stream.
+    #[default]
+    Inherit,
+    /// Collect everything in memory, see [`Host::stdout`](crate::Host::stdout).
+    Capture,
+    /// Emit each line as a `tracing` event with target `guest`.
+    Tracing,
+}
+
+/// Source for the guest's stdin.
+#[derive(Default)]
+pub enum Input {
+    /// Read from the host process's stdin.
+    #[default]
+    Inherit,
+    /// Immediately at end of file.
+    Empty,
+    /// Read from a host stream.
+    Reader(Box<dyn AsyncRead + Send + Sync + Unpin>),
+}
+
+impl Input {
+    pub fn bytes(bytes: impl Into<Vec<u8>>) -> Self {
+        Input::Reader(Box::new(io::Cursor::new(bytes.into())))
+    }
+}
+
+/// Which of the two output streams is being configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Points `stream` at `output` in both contexts, returning the buffer when
+/// the output is captured.
+pub fn configure_output(
+    stream: Stream,
+    output: Output,
+    ctx: &mut WasiCtxBuilder,
+    cli: &mut WasiCliCtx,
+) -> Option<Capture> {
+    match output {
+        Output::Inherit => {
+            match stream {
+                Stream::Stdout => ctx.inherit_stdout(),
+                Stream::Stderr => ctx.inherit_stderr(),
+            };
+            None
+        }
+        Output::Capture => {
+            let capture = Capture::default();
+            let p2 = AsyncWriteStream::new(WRITE_BUDGET, capture.clone());
+            set(stream, ctx, cli, p2, Box::new(capture.clone()));
+            Some(capture)
+        }
+        Output::Tracing => {
+            let lines = Lines::new(stream);
+            let p2 = AsyncWriteStream::new(WRITE_BUDGET, lines.clone());
+            set(stream, ctx, cli, p2, Box::new(Tracing(lines)));
+            None
+        }
+    }
+}
+
+fn set(
+    stream: Stream,
+    ctx: &mut WasiCtxBuilder,
+    cli: &mut WasiCliCtx,
+    p2: AsyncWriteStream,
+    p3: Box<dyn OutputStream + Send>,
+) {
+    let p2 = AsyncStdoutStream::new(p2);
+    match stream {
+        Stream::Stdout => {
+            ctx.stdout(p2);
+            cli.stdout = p3;
+        }
+        Stream::Stderr => {
+            ctx.stderr(p2);
+            cli.stderr = p3;
+        }
+    }
+}
+
+/// Points stdin at `input` in both contexts.
+pub fn configure_input(input: Input, ctx: &mut WasiCtxBuilder, cli: &mut WasiCliCtx) {
+    match input {
+        Input::Inherit => {
+            ctx.inherit_stdin();
+        }
+        Input::Empty => {
+            let reader = Shared::new(Box::new(tokio::io::empty()));
+            ctx.stdin(AsyncStdinStream::new(AsyncReadStream::new(reader.clone())));
+            cli.stdin = Box::new(reader);
+        }
+        Input::Reader(reader) => {
+            let reader = Shared::new(reader);
+            ctx.stdin(AsyncStdinStream::new(AsyncReadStream::new(reader.clone())));
+            cli.stdin = Box::new(reader);
+        }
+    }
+}
+
+/// An in-memory output buffer. Clones share the same contents.
+#[derive(Debug, Clone, Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl AsyncWrite for Capture {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl IsTerminal for Capture {
+    fn is_terminal(&self) -> bool {
+        false
+    }
+}
+
+impl OutputStream for Capture {
+    fn writer(&self) -> Box<dyn AsyncWrite + Send + Sync + Unpin> {
+        Box::new(self.clone())
+    }
+}
+
+/// Preview 3 output stream which forwards lines to `tracing`. Every writer
+/// shares the stream's one [`Lines`] buffer.
+struct Tracing(Lines);
+
+impl IsTerminal for Tracing {
+    fn is_terminal(&self) -> bool {
+        false
+    }
+}
+
+impl OutputStream for Tracing {
+    fn writer(&self) -> Box<dyn AsyncWrite + Send + Sync + Unpin> {
+        Box::new(self.0.clone())
+    }
+}
+
+/// Buffers writes and emits one `tracing` event per complete line. Clones
+/// share one buffer, so a line split across writers, or across preview 2 and
+/// preview 3, is still emitted whole.
+#[derive(Clone)]
+struct Lines(Arc<Mutex<Line>>);
+
+/// The partial line of a stream, emitted as is once the last [`Lines`]
+/// sharing it is dropped.
+struct Line {
+    stream: Stream,
+    bytes: Vec<u8>,
+}
+
+impl Lines {
+    fn new(stream: Stream) -> Self {
+        Self(Arc::new(Mutex::new(Line {
+            stream,
+            bytes: Vec::new(),
+        })))
+    }
+}
+
+impl Line {
+    fn emit(&mut self) {
+        let line = String::from_utf8_lossy(&self.bytes);
+        tracing::info!(target: "guest", stream = self.stream.name(), "{}", line.trim_end());
+        self.bytes.clear();
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        if !self.bytes.is_empty() {
+            self.emit();
+        }
+    }
+}
+
+impl AsyncWrite for Lines {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut line = self.0.lock().unwrap();
+        for &byte in buf {
+            line.bytes.push(byte);
+            if byte == b'\n' {
+                line.emit();
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Leaves a partial line for the stream's other writers to finish.
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A reader shared between the preview 2 and preview 3 stdin, so both make
+/// progress through the same input.
+#[derive(Clone)]
+struct Shared(Arc<Mutex<Box<dyn AsyncRead + Send + Sync + Unpin>>>);
+
+impl Shared {
+    fn new(reader: Box<dyn AsyncRead + Send + Sync + Unpin>) -> Self {
+        Self(Arc::new(Mutex::new(reader)))
+    }
+}
+
+impl AsyncRead for Shared {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut **self.0.lock().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl IsTerminal for Shared {
+    fn is_terminal(&self) -> bool {
+        false
+    }
+}
+
+impl InputStream for Shared {
+    fn reader(&self) -> Box<dyn AsyncRead + Send + Sync + Unpin> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,135 @@
+//! Helpers for moving data through host-side component-model futures and
+//! streams. Each transfer runs inside a `tracing` span that records how many
+//! items went through it, and is counted in [`METRICS`].
+
+use anyhow::Result;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use wasmtime::component::FutureWriter;
+use wasmtime::component::HostFuture;
+use wasmtime::component::HostStream;
+use wasmtime::component::Lift;
+use wasmtime::component::Lower;
+use wasmtime::component::StreamReader;
+use wasmtime::component::StreamWriter;
+use wasmtime::component::VecBuffer;
+use wasmtime::Store;
+
+use crate::metrics::Live;
+use crate::metrics::METRICS;
+use crate::Host;
+
+/// Spawns a task which writes `items` to `tx`, at most `chunk` items at a
+/// time. The stream is closed once every item has been written or the reader
+/// has gone away. The task resolves to the number of items written.
+pub fn spawn_writer<T>(
+    mut tx: StreamWriter<VecBuffer<T>>,
+    items: Vec<T>,
+    chunk: usize,
+) -> JoinHandle<usize>
+where
+    T: Lower + Send + Sync + 'static,
+{
+    let span = tracing::debug_span!("stream.write", items = tracing::field::Empty);
+    tokio::task::spawn(
+        async move {
+            let _live = Live::stream();
+            let mut items = items.into_iter();
+            let mut written = 0;
+            loop {
+                let buf: Vec<T> = items.by_ref().take(chunk).collect();
+                if buf.is_empty() {
+                    break;
+                }
+                let len = buf.len();
+                match tx.write(VecBuffer::from(buf)).into_future().await {
+                    (Some(next), _) => {
+                        written += len;
+                        METRICS
+                            .stream_items
+                            .with_label_values(&["write"])
+                            .inc_by(len as u64);
+                        tracing::trace!(len, "wrote chunk");
+                        tx = next;
+                    }
+                    (None, _) => {
+                        tracing::debug!("reader closed the stream");
+                        break;
+                    }
+                }
+            }
+            tracing::Span::current().record("items", written);
+            written
+        }
+        .instrument(span),
+    )
+}
+
+/// Reads `stream` until the writer closes it, returning every item received.
+pub async fn read_all<T>(store: &mut Store<Host>, stream: HostStream<T>) -> Result<Vec<T>>
+where
+    T: Lift + Send + Sync + 'static,
+{
+    let span = tracing::debug_span!("stream.read", items = tracing::field::Empty);
+    async {
+        let _live = Live::stream();
+        let mut items = Vec::new();
+        let mut reader: StreamReader<Vec<T>> = stream.into_reader(&mut *store);
+        loop {
+            let (next, mut buf) = reader
+                .read(Vec::with_capacity(64 * 1024))
+                .get(&mut *store)
+                .await?;
+            tracing::trace!(len = buf.len(), "read chunk");
+            METRICS
+                .stream_items
+                .with_label_values(&["read"])
+                .inc_by(buf.len() as u64);
+            items.append(&mut buf);
+            match next {
+                Some(next) => reader = next,
+                None => break,
+            }
+        }
+        tracing::Span::current().record("items", items.len());
+        Ok(items)
+    }
+    .instrument(span)
+    .await
+}
+
+/// Spawns a task which writes `value` to `tx`.
+pub fn spawn_future_writer<T>(tx: FutureWriter<T>, value: T) -> JoinHandle<()>
+where
+    T: Lower + Send + Sync + 'static,
+{
+    let span = tracing::debug_span!("future.write");
+    tokio::task::spawn(
+        async move {
+            let _live = Live::future();
+            tx.write(value).into_future().await;
+        }
+        .instrument(span),
+    )
+}
+
+/// Reads the value of `future`, or `None` if the writer went away without
+/// writing one.
+pub async fn read_future<T>(store: &mut Store<Host>, future: HostFuture<T>) -> Result<Option<T>>
+where
+    T: Lift + Send + Sync + 'static,
+{
+    let span = tracing::debug_span!("future.read", ready = tracing::field::Empty);
+    async {
+        let _live = Live::future();
+        let value = future
+            .into_reader(&mut *store)
+            .read()
+            .get(&mut *store)
+            .await?;
+        tracing::Span::current().record("ready", value.is_some());
+        Ok(value)
+    }
+    .instrument(span)
+    .await
+}
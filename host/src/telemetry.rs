@@ -0,0 +1,176 @@
+//! Where the host's `tracing` spans and events go.
+//!
+//! Export calls, `post_return_async`, future and stream transfers and calls
+//! into the host are recorded as spans and events. `HOST_TRACE` selects the
+//! output:
+//!
+//! | `HOST_TRACE`   | Output                                                  |
+//! |----------------|---------------------------------------------------------|
+//! | unset, `fmt`   | human-readable lines on stderr                          |
+//! | `json`         | one JSON object per line on stderr                      |
+//! | `otlp`         | OTLP/HTTP to `OTEL_EXPORTER_OTLP_ENDPOINT`              |
+//!
+//! `RUST_LOG` filters as usual and defaults to [`DEFAULT_FILTER`].
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use wasmtime::component::Accessor;
+use wasmtime::CallHook;
+
+use crate::Host;
+
+pub const TRACE_VAR: &str = "HOST_TRACE";
+
+pub const DEFAULT_FILTER: &str = "warn,host=info,guest=info";
+
+const DEFAULT_ENDPOINT: &str = "http://localhost:4318/v1/traces";
+
+/// Output format for traces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exporter {
+    Fmt,
+    Json,
+    /// OTLP over HTTP, e.g. to a local Jaeger or OpenTelemetry collector.
+    Otlp {
+        endpoint: String,
+    },
+}
+
+impl Exporter {
+    /// Reads [`TRACE_VAR`], falling back to [`Exporter::Fmt`].
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(TRACE_VAR).as_deref() {
+            Err(_) | Ok("") | Ok("fmt") => Ok(Exporter::Fmt),
+            Ok("json") => Ok(Exporter::Json),
+            Ok("otlp") => Ok(Exporter::Otlp {
+                endpoint: std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                    .map(|base| format!("{}/v1/traces", base.trim_end_matches('/')))
+                    .unwrap_or_else(|_| DEFAULT_ENDPOINT.to_owned()),
+            }),
+            Ok(other) => bail!("{TRACE_VAR}={other}: expected `fmt`, `json` or `otlp`"),
+        }
+    }
+}
+
+static PROVIDER: OnceLock<Option<SdkTracerProvider>> = OnceLock::new();
+
+/// Installs the global subscriber described by [`Exporter::from_env`]. Only
+/// the first call has an effect, and nothing is installed if the embedder
+/// already set a global subscriber of its own.
+///
+/// The library never calls this outside its tests; binaries call it at the
+/// start of `main`.
+pub fn install() {
+    PROVIDER.get_or_init(|| {
+        let exporter = Exporter::from_env().unwrap_or_else(|err| {
+            eprintln!("{err:#}, using `fmt`");
+            Exporter::Fmt
+        });
+        match init(exporter) {
+            Ok(provider) => provider,
+            Err(err) => {
+                eprintln!("failed to set up tracing: {err:#}");
+                None
+            }
+        }
+    });
+}
+
+/// Flushes spans still buffered for the OTLP exporter. Call before exiting.
+pub fn shutdown() {
+    if let Some(Some(provider)) = PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            eprintln!("failed to flush traces: {err}");
+        }
+    }
+}
+
+fn init(exporter: Exporter) -> Result<Option<SdkTracerProvider>> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let registry = tracing_subscriber::registry().with(filter);
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    match exporter {
+        Exporter::Fmt => {
+            // Another subscriber being installed already is not an error.
+            let _ = registry.with(fmt).try_init();
+            Ok(None)
+        }
+        Exporter::Json => {
+            let _ = registry
+                .with(fmt.json().with_current_span(true).with_span_list(true))
+                .try_init();
+            Ok(None)
+        }
+        Exporter::Otlp { endpoint } => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()?;
+            let provider = SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name("host").build())
+                .build();
+            let tracer = provider.tracer("host");
+            let _ = registry
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .try_init();
+            Ok(Some(provider))
+        }
+    }
+}
+
+/// Turns [`CallHook`] transitions into events with the time spent on each
+/// side of the boundary. Host calls made by async WASI functions only cover
+/// the synchronous part of the call; the rest shows up in the export's span.
+///
+/// A call hook is not told which import is being called, so the host's own
+/// functions name themselves with [`import`]. Calls into WASI functions the
+/// host does not replace are recorded as `wasi`.
+#[derive(Debug, Default)]
+pub(crate) struct Transitions {
+    wasm: Option<Instant>,
+    host: Option<Instant>,
+    import: Option<&'static str>,
+}
+
+impl Transitions {
+    pub(crate) fn record(&mut self, hook: CallHook) {
+        match hook {
+            CallHook::CallingWasm => self.wasm = Some(Instant::now()),
+            CallHook::ReturningFromWasm => {
+                if let Some(start) = self.wasm.take() {
+                    let elapsed_us = start.elapsed().as_micros() as u64;
+                    tracing::trace!(target: "host::calls", elapsed_us, "guest returned");
+                }
+            }
+            CallHook::CallingHost => {
+                self.host = Some(Instant::now());
+                self.import = None;
+            }
+            CallHook::ReturningFromHost => {
+                let import = self.import.take().unwrap_or("wasi");
+                if let Some(start) = self.host.take() {
+                    let elapsed_us = start.elapsed().as_micros() as u64;
+                    tracing::debug!(target: "host::calls", import, elapsed_us, "host call returned");
+                }
+            }
+        }
+    }
+}
+
+/// Names the host call in progress `name`, as `interface#function`. Call it
+/// from the synchronous part of a host function.
+pub(crate) fn import(accessor: &mut Accessor<Host>, name: &'static str) {
+    accessor.with(|mut view| view.get().transitions.import = Some(name));
+}
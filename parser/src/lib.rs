@@ -0,0 +1,36 @@
+pub mod bindings {
+    wit_bindgen::generate!({
+        world: "pkg:nexmark/csv",
+        path: "interface.wit",
+    });
+
+    pub struct Component;
+
+    export!(Component);
+}
+
+use bindings::exports::pkg::nexmark::parser::Bid;
+use bindings::exports::pkg::nexmark::parser::Guest;
+
+impl Guest for bindings::Component {
+    fn parse_bid(line: String) -> Result<Bid, String> {
+        let fields: Vec<&str> = line.trim_end().splitn(7, ',').collect();
+        let [auction, bidder, price, channel, url, date_time, extra] = fields[..] else {
+            return Err(format!("expected 7 fields, found {}", fields.len()));
+        };
+        let number = |name: &str, field: &str| {
+            field
+                .parse::<u64>()
+                .map_err(|e| format!("invalid {name} {field:?}: {e}"))
+        };
+        Ok(Bid {
+            auction: number("auction", auction)?,
+            bidder: number("bidder", bidder)?,
+            price: number("price", price)?,
+            channel: channel.to_owned(),
+            url: url.to_owned(),
+            date_time: number("date_time", date_time)?,
+            extra: extra.to_owned(),
+        })
+    }
+}
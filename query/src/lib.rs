@@ -0,0 +1,40 @@
+pub mod bindings {
+    wit_bindgen::generate!({
+        world: "pkg:query/query",
+        path: [
+            // Note: These imports are order-sensitive.
+            "../parser/interface.wit",
+            "interface.wit",
+        ],
+        async: {
+            exports: [
+                "pkg:query/query#top-bids",
+            ],
+            imports: []
+        },
+    });
+
+    pub struct Component;
+
+    export!(Component);
+}
+
+use bindings::exports::pkg::query::query::Guest;
+use bindings::pkg::nexmark::parser::parse_bid;
+use bindings::pkg::nexmark::parser::Bid;
+use wit_bindgen::rt::async_support::futures::StreamExt;
+use wit_bindgen::rt::async_support::StreamReader;
+
+impl Guest for bindings::Component {
+    /// The `n` highest bids among `lines`, highest first. Lines the parser
+    /// rejects are skipped.
+    async fn top_bids(mut lines: StreamReader<String>, n: u32) -> Vec<Bid> {
+        let mut bids = Vec::new();
+        while let Some(chunk) = lines.next().await {
+            bids.extend(chunk.iter().filter_map(|line| parse_bid(line).ok()));
+        }
+        bids.sort_by(|a, b| b.price.cmp(&a.price));
+        bids.truncate(n as usize);
+        bids
+    }
+}